@@ -0,0 +1,62 @@
+#!/bin/bash
+
+# Cargo runner: writes the kernel's function symbols into its .ksyms section,
+# so backtraces can name functions, then boots it with bootimage as before.
+# The section keeps its size, so no address in the kernel moves.
+# Set NM and OBJCOPY to use other binutils, e.g. llvm-nm and llvm-objcopy.
+# Without them the kernel boots as it is, its backtraces without names.
+# A table that outgrows the section is an error, though, and nothing boots.
+
+kernel=$1
+nm=${NM:-nm}
+objcopy=${OBJCOPY:-objcopy}
+
+warn() {
+    echo "embed-symbols.sh: $*, booting without symbols" >&2
+}
+
+# Every other failure warns and gives up on the symbols, never on booting.
+embed() (
+    for tool in "$nm" "$objcopy" truncate; do
+        if ! command -v "$tool" > /dev/null; then
+            warn "$tool not found"
+            return 1
+        fi
+    done
+    table=$(mktemp) || { warn "mktemp failed"; return 1; }
+    trap 'rm -f "$table" "$table.section"' EXIT
+
+    if ! "$objcopy" --dump-section .ksyms="$table.section" "$kernel"; then
+        warn "could not read .ksyms"
+        return 1
+    fi
+    size=$(wc -c < "$table.section")
+
+    # "address size name", with size 0 for symbols that have none.
+    "$nm" -n -C -S "$kernel" | sed -nE \
+        -e 's/^([0-9a-f]+) ([0-9a-f]+) [tTwW] (.*)$/\1 \2 \3/p' \
+        -e 's/^([0-9a-f]+) [tTwW] (.*)$/\1 0 \2/p' > "$table"
+    if [ "${PIPESTATUS[0]}" -ne 0 ]; then
+        warn "$nm failed"
+        return 1
+    fi
+
+    if [ "$(wc -c < "$table")" -ge "$size" ]; then
+        echo "embed-symbols.sh: error: symbol table ($(wc -c < "$table") bytes)" \
+            "does not fit in .ksyms ($size bytes)," \
+            "raise SYMBOL_TABLE_SIZE in src/backtrace.rs" >&2
+        return 2
+    fi
+    if ! truncate -s "$size" "$table" ||
+        ! "$objcopy" --update-section .ksyms="$table" "$kernel"; then
+        warn "could not write .ksyms"
+        return 1
+    fi
+)
+
+embed
+if [ $? -eq 2 ]; then
+    exit 1
+fi
+
+exec bootimage runner "$@"
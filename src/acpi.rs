@@ -0,0 +1,369 @@
+use core::{ptr, slice};
+
+use lazy_static::lazy_static;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    PhysAddr,
+};
+
+use crate::{
+    memory::{map_mmio, phys_to_virt},
+    pit,
+};
+
+/// Where the BIOS keeps the segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+pub const HEADER_SIZE: usize = 36;
+
+/// The root table, as its physical address and whether it is an XSDT with
+/// 64-bit entries rather than an RSDT with 32-bit ones.
+#[derive(Debug, Clone, Copy)]
+struct Root {
+    address: u64,
+    extended: bool,
+}
+
+lazy_static! {
+    static ref ROOT: Option<Root> = find_root();
+}
+
+/// Physical memory is mapped whole by the bootloader, ACPI areas included.
+unsafe fn physical_bytes(address: u64, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(PhysAddr::new(address)).as_ptr(), len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// Looks for the RSDP on 16-byte boundaries in `start..end`.
+fn scan_for_rsdp(start: u64, end: u64) -> Option<Root> {
+    (start..end).step_by(16).find_map(|address| {
+        let rsdp = unsafe { physical_bytes(address, 36) };
+        if &rsdp[..8] != RSDP_SIGNATURE || !checksum_ok(&rsdp[..20]) {
+            return None;
+        }
+        // Revision 2 and later add the XSDT address and an extended checksum.
+        let xsdt = read_u64(rsdp, 24);
+        if rsdp[15] >= 2 && xsdt != 0 && checksum_ok(&rsdp[..36]) {
+            Some(Root {
+                address: xsdt,
+                extended: true,
+            })
+        } else {
+            Some(Root {
+                address: u64::from(read_u32(rsdp, 16)),
+                extended: false,
+            })
+        }
+    })
+}
+
+fn find_root() -> Option<Root> {
+    let pointer = unsafe { physical_bytes(EBDA_SEGMENT_POINTER, 2) };
+    let ebda = u64::from(read_u16(pointer, 0)) << 4;
+    let in_ebda = if ebda != 0 {
+        scan_for_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+/// The whole table at `address`, or `None` if its checksum is wrong.
+fn table_at(address: u64) -> Option<&'static [u8]> {
+    let header = unsafe { physical_bytes(address, HEADER_SIZE) };
+    let length = read_u32(header, 4) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = unsafe { physical_bytes(address, length) };
+    if checksum_ok(table) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Addresses of every table the root table lists.
+fn table_addresses() -> impl Iterator<Item = u64> {
+    let (root, entry_size) = match *ROOT {
+        Some(root) => (table_at(root.address), if root.extended { 8 } else { 4 }),
+        None => (None, 4),
+    };
+    let entries = root.map_or(&[][..], |root| &root[HEADER_SIZE..]);
+    entries.chunks_exact(entry_size).map(move |entry| {
+        if entry_size == 8 {
+            read_u64(entry, 0)
+        } else {
+            u64::from(read_u32(entry, 0))
+        }
+    })
+}
+
+/// Finds the first table with `signature`, header included.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses()
+        .filter_map(table_at)
+        .find(|table| &table[..4] == signature)
+}
+
+pub fn is_available() -> bool {
+    ROOT.is_some()
+}
+
+/// Address spaces of a generic address structure.
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+/// FADT flag saying the reset register may be used.
+const RESET_REG_SUP: u32 = 1 << 10;
+
+const SCI_EN: u16 = 1;
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// A register given as an ACPI generic address structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8], offset: usize) -> GenericAddress {
+        GenericAddress {
+            space: bytes[offset],
+            address: read_u64(bytes, offset + 4),
+        }
+    }
+}
+
+/// The parts of the fixed ACPI description table power management needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    /// The reset register and the value to write to it, if the machine has one.
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+impl Fadt {
+    /// Parses a FADT of any revision; the fields later revisions added are
+    /// used only when the table is long enough to have them.
+    pub fn parse(table: &[u8]) -> Option<Fadt> {
+        if table.len() < 116 || &table[..4] != b"FACP" {
+            return None;
+        }
+        let mut fadt = Fadt {
+            dsdt: u64::from(read_u32(table, 40)),
+            smi_command: read_u32(table, 48),
+            acpi_enable: table[52],
+            pm1a_control: read_u32(table, 64) as u16,
+            pm1b_control: read_u32(table, 68) as u16,
+            reset: None,
+        };
+        if table.len() >= 129 && read_u32(table, 112) & RESET_REG_SUP != 0 {
+            fadt.reset = Some((GenericAddress::parse(table, 116), table[128]));
+        }
+        if table.len() >= 148 && read_u64(table, 140) != 0 {
+            fadt.dsdt = read_u64(table, 140);
+        }
+        // The extended blocks only stand in for missing 32-bit ones.
+        let extended_io = |offset: usize| {
+            if table.len() < offset + 12 {
+                return 0;
+            }
+            let address = GenericAddress::parse(table, offset);
+            if address.space == SPACE_IO {
+                address.address as u16
+            } else {
+                0
+            }
+        };
+        if fadt.pm1a_control == 0 {
+            fadt.pm1a_control = extended_io(172);
+        }
+        if fadt.pm1b_control == 0 {
+            fadt.pm1b_control = extended_io(184);
+        }
+        Some(fadt)
+    }
+}
+
+pub fn fadt() -> Option<Fadt> {
+    find_table(b"FACP").and_then(Fadt::parse)
+}
+
+/// Decodes an AML integer, giving its value and its length.
+fn aml_integer(aml: &[u8]) -> Option<(u16, usize)> {
+    match *aml.first()? {
+        0x00 => Some((0, 1)),
+        0x01 => Some((1, 1)),
+        0xFF => Some((0xFFFF, 1)),
+        0x0A => Some((u16::from(*aml.get(1)?), 2)),
+        0x0B => Some((read_u16(aml.get(..3)?, 1), 3)),
+        0x0C => Some((read_u32(aml.get(..5)?, 1) as u16, 5)),
+        _ => None,
+    }
+}
+
+/// Finds the `\_S5` object in the AML of a DSDT and gives its SLP_TYPa and
+/// SLP_TYPb values. The object is a `Name` bound to a package whose first
+/// two elements are those values.
+pub fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let start = aml.windows(4).enumerate().find_map(|(pos, name)| {
+        let name_op = match pos {
+            0 => None,
+            1 => Some(aml[0]),
+            // The name may be written with a root prefix, `\_S5_`.
+            _ if aml[pos - 1] == b'\\' => Some(aml[pos - 2]),
+            _ => Some(aml[pos - 1]),
+        };
+        if name == b"_S5_" && name_op == Some(0x08) && aml.get(pos + 4) == Some(&0x12) {
+            Some(pos + 5)
+        } else {
+            None
+        }
+    })?;
+    // The package length encoding tells how many more bytes it takes.
+    let length_bytes = usize::from(*aml.get(start)? >> 6) + 1;
+    let mut pos = start + length_bytes + 1;
+    let (slp_typa, len) = aml_integer(aml.get(pos..)?)?;
+    pos += len;
+    let (slp_typb, _) = aml_integer(aml.get(pos..)?)?;
+    Some((slp_typa, slp_typb))
+}
+
+/// Hands the machine to ACPI mode through the SMI command port, if the
+/// firmware left it in legacy mode.
+fn enable_acpi(fadt: &Fadt) {
+    let mut control = Port::<u16>::new(fadt.pm1a_control);
+    if unsafe { control.read() } & SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            return;
+        }
+        pit::wait_polled(1);
+    }
+}
+
+/// Powers the machine off by entering sleep state S5. Only returns if that
+/// didn't work.
+pub fn shutdown() -> Result<(), &'static str> {
+    let fadt = fadt().ok_or("No ACPI FADT found.")?;
+    if fadt.pm1a_control == 0 {
+        return Err("FADT has no PM1 control block.");
+    }
+    let dsdt = table_at(fadt.dsdt).ok_or("No valid ACPI DSDT found.")?;
+    let (slp_typa, slp_typb) = parse_s5(&dsdt[HEADER_SIZE..]).ok_or("DSDT has no \\_S5 object.")?;
+    enable_acpi(&fadt);
+    // Callers like the panic handler run with interrupts off and must stay so.
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    unsafe {
+        let mut pm1a = Port::<u16>::new(fadt.pm1a_control);
+        let value = pm1a.read() & !(0b111 << SLP_TYP_SHIFT);
+        if fadt.pm1b_control != 0 {
+            let mut pm1b = Port::<u16>::new(fadt.pm1b_control);
+            let value = pm1b.read() & !(0b111 << SLP_TYP_SHIFT);
+            pm1b.write(value | slp_typb << SLP_TYP_SHIFT | SLP_EN);
+        }
+        pm1a.write(value | slp_typa << SLP_TYP_SHIFT | SLP_EN);
+    }
+    pit::wait_polled(1000);
+    if enabled {
+        interrupts::enable();
+    }
+    Err("Machine did not power off.")
+}
+
+/// Writes the FADT reset value to the reset register.
+fn reset_register() -> Result<(), &'static str> {
+    let (register, value) = fadt()
+        .and_then(|fadt| fadt.reset)
+        .ok_or("No ACPI reset register.")?;
+    match register.space {
+        SPACE_IO => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        SPACE_MEMORY => {
+            let address = map_mmio(PhysAddr::new(register.address), 1)?;
+            unsafe { ptr::write_volatile(address.as_mut_ptr::<u8>(), value) };
+        }
+        _ => return Err("ACPI reset register is in an unsupported address space."),
+    }
+    Ok(())
+}
+
+/// Resets the machine through the ACPI reset register, falling back to
+/// pulsing the reset line of the keyboard controller. Only returns if
+/// neither worked.
+pub fn reboot() -> Result<(), &'static str> {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if reset_register().is_ok() {
+        pit::wait_polled(500);
+    }
+    unsafe {
+        let mut status = Port::<u8>::new(KBC_STATUS);
+        for _ in 0..1000 {
+            if status.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+            pit::wait_polled(1);
+        }
+        status.write(KBC_PULSE_RESET);
+    }
+    pit::wait_polled(500);
+    if enabled {
+        interrupts::enable();
+    }
+    Err("Machine did not reset.")
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_ok(&[0x10, 0xF0]));
+    assert!(!checksum_ok(&[0x10, 0xEF]));
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // Name (_S5_, Package (4) { 0x05, Zero, Zero, Zero }), as QEMU has it.
+    let aml = [
+        0x10, 0x08, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    let rooted = [
+        0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x02, 0x0A, 0x07, 0x0A, 0x07,
+    ];
+    assert_eq!(parse_s5(&rooted), Some((7, 7)));
+    // Without the Name op in front it is no definition of _S5_.
+    assert_eq!(parse_s5(&aml[3..]), None);
+}
@@ -36,6 +36,11 @@ static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator:
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 15000 * 1024; // 1500 KiB
 
+/// Bytes of the kernel heap currently handed out.
+pub fn heap_used() -> usize {
+    ALLOCATOR.lock().used()
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -6,14 +6,19 @@ use core::ptr;
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    used: usize,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            used: 0,
         }
     }
+    pub fn used(&self) -> usize {
+        self.used
+    }
     fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
@@ -90,6 +95,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.used += size;
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -98,6 +104,8 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.used -= size;
+        allocator.add_free_region(ptr as usize, size)
     }
 }
@@ -0,0 +1,384 @@
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+use crate::{
+    acpi::{self, read_u16, read_u32, read_u64},
+    interrupts::{InterruptIndex, PICS, PIC_1_OFFSET},
+    memory::map_mmio,
+    pit, println,
+};
+
+/// Vector the local APIC raises for spurious interrupts; it must not get an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, as offsets from its base.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// PIT periods to count the local APIC timer over when calibrating it.
+const CALIBRATION_TICKS: u64 = 10;
+
+// I/O APIC registers, reached through its select and window registers.
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+/// Virtual address of the local APIC, or 0 while interrupts go through the PIC.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+/// Where each legacy IRQ ends up, once the MADT's overrides are applied.
+static ROUTES: Mutex<[Option<Route>; 16]> = Mutex::new([None; 16]);
+
+/// An entry of the MADT's interrupt controller structure list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MadtEntry {
+    LocalApic { enabled: bool },
+    IoApic { address: u32, gsi_base: u32 },
+    SourceOverride { irq: u8, gsi: u32, flags: u16 },
+    LocalApicAddress(u64),
+    Other,
+}
+
+fn madt_entries(madt: &[u8]) -> impl Iterator<Item = MadtEntry> + '_ {
+    // The entries follow the header, the local APIC address and the flags.
+    let mut pos = acpi::HEADER_SIZE + 8;
+    core::iter::from_fn(move || {
+        let kind = *madt.get(pos)?;
+        let len = usize::from(*madt.get(pos + 1)?);
+        if len < 2 || pos + len > madt.len() {
+            return None;
+        }
+        let entry = &madt[pos..pos + len];
+        pos += len;
+        Some(match (kind, len) {
+            (0, 8) => MadtEntry::LocalApic {
+                enabled: read_u32(entry, 4) & 1 != 0,
+            },
+            (1, 12) => MadtEntry::IoApic {
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            },
+            (2, 10) => MadtEntry::SourceOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            },
+            (5, 12) => MadtEntry::LocalApicAddress(read_u64(entry, 4)),
+            _ => MadtEntry::Other,
+        })
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Route {
+    gsi: u32,
+    active_low: bool,
+    level: bool,
+}
+
+impl Route {
+    /// ISA interrupts are edge-triggered and active high unless overridden.
+    fn identity(irq: u8) -> Route {
+        Route {
+            gsi: u32::from(irq),
+            active_low: false,
+            level: false,
+        }
+    }
+
+    fn overridden(gsi: u32, flags: u16) -> Route {
+        Route {
+            gsi,
+            active_low: flags & 0b11 == 0b11,
+            level: flags >> 2 & 0b11 == 0b11,
+        }
+    }
+}
+
+/// Legacy IRQs 0-15 mapped to global system interrupts. A line another IRQ
+/// was moved onto, like the PIT's IRQ0 on GSI 2, gets no route of its own.
+fn legacy_routes(madt: &[u8]) -> [Option<Route>; 16] {
+    let mut routes = [None; 16];
+    for irq in 0..16u8 {
+        routes[usize::from(irq)] = Some(Route::identity(irq));
+    }
+    for entry in madt_entries(madt) {
+        if let MadtEntry::SourceOverride { irq, gsi, flags } = entry {
+            if irq < 16 {
+                routes[usize::from(irq)] = Some(Route::overridden(gsi, flags));
+            }
+        }
+    }
+    for entry in madt_entries(madt) {
+        if let MadtEntry::SourceOverride { irq, gsi, .. } = entry {
+            let line = gsi as usize;
+            if u32::from(irq) != gsi
+                && line < 16
+                && routes[line] == Some(Route::identity(gsi as u8))
+            {
+                routes[line] = None;
+            }
+        }
+    }
+    routes
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), register);
+            ptr::read_volatile((self.base + IOAPIC_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), register);
+            ptr::write_volatile((self.base + IOAPIC_WINDOW).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.pins
+    }
+
+    fn redirect(&self, gsi: u32, low: u32, destination: u8) {
+        let register = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        self.write(register, REDIRECT_MASKED);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+
+    fn set_masked(&self, gsi: u32, masked: bool) {
+        let register = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        let low = self.read(register);
+        if masked {
+            self.write(register, low | REDIRECT_MASKED);
+        } else {
+            self.write(register, low & !REDIRECT_MASKED);
+        }
+    }
+}
+
+fn lapic_read(base: VirtAddr, register: usize) -> u32 {
+    unsafe { ptr::read_volatile((base + register).as_ptr::<u32>()) }
+}
+
+fn lapic_write(base: VirtAddr, register: usize, value: u32) {
+    unsafe { ptr::write_volatile((base + register).as_mut_ptr::<u32>(), value) }
+}
+
+/// Whether interrupts are delivered by the local APIC instead of the PIC.
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Acknowledges the interrupt being serviced.
+pub fn end_of_interrupt() {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    if base != 0 {
+        lapic_write(VirtAddr::new(base), LAPIC_EOI, 0);
+    }
+}
+
+/// Unmasks legacy IRQ `irq` on the I/O APIC its route leads to.
+pub fn unmask_irq(irq: u8) {
+    let route = match ROUTES.lock().get(usize::from(irq)).copied().flatten() {
+        Some(route) => route,
+        None => return,
+    };
+    if let Some(io_apic) = IO_APICS.lock().iter().find(|io| io.handles(route.gsi)) {
+        io_apic.set_masked(route.gsi, false);
+    }
+}
+
+/// Counts how fast the local APIC timer runs against the PIT, giving the
+/// initial count for one tick of [`pit::TIMER_HZ`].
+fn calibrate_timer(lapic: VirtAddr) -> u32 {
+    lapic_write(lapic, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(lapic, LAPIC_LVT_TIMER, LVT_MASKED);
+    lapic_write(lapic, LAPIC_TIMER_INITIAL, u32::MAX);
+    pit::wait_clocks(pit::DIVISOR * CALIBRATION_TICKS);
+    let elapsed = u32::MAX - lapic_read(lapic, LAPIC_TIMER_CURRENT);
+    lapic_write(lapic, LAPIC_TIMER_INITIAL, 0);
+    elapsed / CALIBRATION_TICKS as u32
+}
+
+fn has_apic() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Moves interrupt delivery from the 8259 PICs to the local APIC and I/O
+/// APICs the MADT describes. The local APIC timer takes over the tick at the
+/// PIT's rate; the IRQ lines unmasked on the PICs are unmasked on the I/O APIC.
+fn enable() -> Result<(usize, usize), &'static str> {
+    if !has_apic() {
+        return Err("CPU has no local APIC");
+    }
+    let madt = acpi::find_table(b"APIC").ok_or("no MADT in the ACPI tables")?;
+    let mut lapic_phys = u64::from(read_u32(madt, acpi::HEADER_SIZE));
+    let mut cpus = 0;
+    let mut io_apics = Vec::new();
+    for entry in madt_entries(madt) {
+        match entry {
+            MadtEntry::LocalApic { enabled: true } => cpus += 1,
+            MadtEntry::LocalApicAddress(address) => lapic_phys = address,
+            MadtEntry::IoApic { address, gsi_base } => {
+                let base = map_mmio(PhysAddr::new(u64::from(address)), 0x20)?;
+                let mut io_apic = IoApic {
+                    base,
+                    gsi_base,
+                    pins: 0,
+                };
+                io_apic.pins = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xFF) + 1;
+                io_apics.push(io_apic);
+            }
+            _ => {}
+        }
+    }
+    if io_apics.is_empty() {
+        return Err("no I/O APIC in the MADT");
+    }
+    let routes = legacy_routes(madt);
+    let lapic = map_mmio(PhysAddr::new(lapic_phys), 0x400)?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            let mut msr = Msr::new(IA32_APIC_BASE);
+            let value = msr.read();
+            msr.write(value | APIC_BASE_ENABLE);
+        }
+        lapic_write(lapic, LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+        lapic_write(lapic, LAPIC_TPR, 0);
+        let ticks = calibrate_timer(lapic);
+        if ticks == 0 {
+            return Err("local APIC timer did not run");
+        }
+
+        for io_apic in &io_apics {
+            for pin in 0..io_apic.pins {
+                io_apic.set_masked(io_apic.gsi_base + pin, true);
+            }
+        }
+        let destination = (lapic_read(lapic, LAPIC_ID) >> 24) as u8;
+        let mut pics = PICS.lock();
+        let [master, slave] = unsafe { pics.read_masks() };
+        let pic_masks = u16::from(slave) << 8 | u16::from(master);
+        // IRQ0 stays masked, the local APIC timer replaces the PIT's tick.
+        for irq in 1..16u8 {
+            let route = match routes[usize::from(irq)] {
+                Some(route) => route,
+                None => continue,
+            };
+            let io_apic = match io_apics.iter().find(|io| io.handles(route.gsi)) {
+                Some(io_apic) => io_apic,
+                None => continue,
+            };
+            let mut low = u32::from(PIC_1_OFFSET + irq);
+            if route.active_low {
+                low |= REDIRECT_ACTIVE_LOW;
+            }
+            if route.level {
+                low |= REDIRECT_LEVEL;
+            }
+            if pic_masks & 1 << irq != 0 {
+                low |= REDIRECT_MASKED;
+            }
+            io_apic.redirect(route.gsi, low, destination);
+        }
+        unsafe { pics.write_masks(0xFF, 0xFF) };
+        drop(pics);
+
+        lapic_write(lapic, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(
+            lapic,
+            LAPIC_LVT_TIMER,
+            LVT_PERIODIC | u32::from(InterruptIndex::Timer as u8),
+        );
+        lapic_write(lapic, LAPIC_TIMER_INITIAL, ticks);
+
+        let io_apic_count = io_apics.len();
+        *ROUTES.lock() = routes;
+        *IO_APICS.lock() = io_apics;
+        LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+        Ok((cpus, io_apic_count))
+    })
+}
+
+/// Switches to APIC interrupt delivery, staying on the PICs if that fails.
+pub fn init() {
+    match enable() {
+        Ok((cpus, io_apics)) => println!(
+            "[OK] APIC: local APIC timer at {} Hz, {} CPU(s), {} I/O APIC(s).",
+            pit::TIMER_HZ,
+            cpus,
+            io_apics
+        ),
+        Err(err) => println!("[INFO] APIC: {}, staying on the 8259 PIC.", err),
+    }
+}
+
+#[test_case]
+fn test_madt_routes() {
+    let mut madt = [0u8; acpi::HEADER_SIZE + 8 + 12 + 10 + 10];
+    let mut pos = acpi::HEADER_SIZE + 8;
+    // I/O APIC 0 at 0xFEC00000, GSIs from 0.
+    madt[pos..pos + 12].copy_from_slice(&[1, 12, 0, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0]);
+    pos += 12;
+    // IRQ0 moved to GSI 2.
+    madt[pos..pos + 10].copy_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    pos += 10;
+    // IRQ 9, level-triggered and active high.
+    madt[pos..pos + 10].copy_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0b1101, 0]);
+
+    assert_eq!(
+        madt_entries(&madt).next(),
+        Some(MadtEntry::IoApic {
+            address: 0xFEC0_0000,
+            gsi_base: 0
+        })
+    );
+    let routes = legacy_routes(&madt);
+    assert_eq!(routes[0].map(|route| route.gsi), Some(2));
+    assert_eq!(routes[2], None);
+    assert_eq!(
+        routes[9],
+        Some(Route {
+            gsi: 9,
+            active_low: false,
+            level: true
+        })
+    );
+    assert_eq!(routes[1], Some(Route::identity(1)));
+}
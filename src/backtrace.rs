@@ -0,0 +1,147 @@
+use core::{arch::asm, fmt, ptr, str};
+
+use x86_64::VirtAddr;
+
+use crate::{exceptions::report_line as report, memory};
+
+/// Room for the symbol table `embed-symbols.sh` writes into the kernel after
+/// it is linked. It is part of every kernel image, so it is kept to about
+/// what the kernel's own table needs; the script refuses to boot a kernel whose
+/// table outgrew it.
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+const MAX_FRAMES: usize = 32;
+/// How far apart two frames of one stack may be before the chain is taken
+/// to be broken.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// `nm` lines of "address size name", sorted by address, padded with zeros.
+/// Mutable and exported, so the compiler can't assume it stays all zeros.
+#[no_mangle]
+#[used]
+#[link_section = ".ksyms"]
+static mut KERNEL_SYMBOLS: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+fn symbol_table() -> &'static [u8] {
+    let table = unsafe { &*ptr::addr_of!(KERNEL_SYMBOLS) };
+    let len = table
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(table.len());
+    &table[..len]
+}
+
+/// The function `address` lies in and how far into it, looked up in `table`.
+fn lookup(table: &[u8], address: u64) -> Option<(&str, u64)> {
+    let mut found = None;
+    for line in table.split(|byte| *byte == b'\n') {
+        let mut fields = match str::from_utf8(line) {
+            Ok(line) => line.splitn(3, ' '),
+            Err(_) => continue,
+        };
+        let (start, size, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(size), Some(name)) => (start, size, name),
+            _ => continue,
+        };
+        let (start, size) = match (
+            u64::from_str_radix(start, 16),
+            u64::from_str_radix(size, 16),
+        ) {
+            (Ok(start), Ok(size)) => (start, size),
+            _ => continue,
+        };
+        if start > address {
+            break;
+        }
+        // Symbols from assembly have no size; they reach up to the next one.
+        found = if size == 0 || address - start < size {
+            Some((name, address - start))
+        } else {
+            None
+        };
+    }
+    found
+}
+
+/// An address printed as the symbol it lies in, if the table knows it.
+struct Location(u64);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(symbol_table(), self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "<unknown>"),
+        }
+    }
+}
+
+fn readable(address: u64) -> bool {
+    match VirtAddr::try_new(address) {
+        // Trust the frame chain when the page tables can't be asked.
+        Ok(address) => memory::is_mapped(address).unwrap_or(true),
+        Err(_) => false,
+    }
+}
+
+/// Calls `f` with the return address of every frame in the chain of saved
+/// frame pointers starting at `rbp`, innermost first.
+fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !readable(rbp) || !readable(rbp + 8) {
+            return;
+        }
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        // Callers' frames sit above their callees' on the same stack.
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+/// Prints the call stack to the screen and the serial port, starting at the
+/// instruction `rip` when known and then going up the frames from `rbp`.
+pub fn print_from(rip: Option<u64>, rbp: u64) {
+    report(format_args!("Backtrace:"));
+    let mut index = 0;
+    let mut frame = |address: u64, location: u64| {
+        report(format_args!(
+            "  #{:<2} {:#018x}  {}",
+            index,
+            address,
+            Location(location)
+        ));
+        index += 1;
+    };
+    if let Some(rip) = rip {
+        frame(rip, rip);
+    }
+    // A return address is just past the call, which may be a function's
+    // last instruction, so look up the byte before it.
+    walk(rbp, |address| frame(address, address - 1));
+    if symbol_table().is_empty() {
+        report(format_args!(
+            "  (no symbol table; boot through embed-symbols.sh to get names)"
+        ));
+    }
+}
+
+/// Prints the call stack of the caller.
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    print_from(None, rbp);
+}
+
+#[test_case]
+fn test_lookup() {
+    let table = b"1000 20 kukios::first\n1020 0 exception_stub_0\n2000 10 kukios::second\n";
+    assert_eq!(lookup(table, 0x1004), Some(("kukios::first", 4)));
+    assert_eq!(lookup(table, 0x1100), Some(("exception_stub_0", 0xE0)));
+    assert_eq!(lookup(table, 0x2010), None);
+    assert_eq!(lookup(table, 0x800), None);
+}
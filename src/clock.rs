@@ -0,0 +1,212 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{interrupts::uptime_millis, println, rtc};
+
+/// Unix time in milliseconds at boot, valid once `CLOCK_SET` is.
+static BOOT_TIME_MILLIS: AtomicU64 = AtomicU64::new(0);
+static CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+/// Milliseconds since the Unix epoch, or `None` until something set the clock.
+pub fn now_millis() -> Option<u64> {
+    if CLOCK_SET.load(Ordering::SeqCst) {
+        Some(BOOT_TIME_MILLIS.load(Ordering::SeqCst) + uptime_millis())
+    } else {
+        None
+    }
+}
+
+pub fn now() -> Option<DateTime> {
+    now_millis().map(|millis| DateTime::from_unix(millis / 1000))
+}
+
+/// Sets the wall clock to `unix_millis`, which is taken to be the time now.
+pub fn set_millis(unix_millis: u64) {
+    BOOT_TIME_MILLIS.store(
+        unix_millis.saturating_sub(uptime_millis()),
+        Ordering::SeqCst,
+    );
+    CLOCK_SET.store(true, Ordering::SeqCst);
+}
+
+/// Starts the clock from the RTC; NTP corrects it once the network is up.
+pub fn init() {
+    match rtc::read() {
+        Ok(time) => {
+            set_millis(time.to_unix() * 1000);
+            println!("[OK] Clock set from the RTC: {}", time);
+        }
+        Err(err) => println!("[INFO] {} The clock is not set.", err),
+    }
+}
+
+/// A UTC calendar date and time, to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Parses `YYYY-MM-DD` and `HH:MM[:SS]`.
+    pub fn parse(date: &str, time: &str) -> Option<DateTime> {
+        let mut date = date.split('-');
+        let mut time = time.split(':');
+        let parsed = DateTime {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.parse().ok()?,
+            minute: time.next()?.parse().ok()?,
+            second: time.next().unwrap_or("0").parse().ok()?,
+        };
+        if date.next().is_some() || time.next().is_some() || !parsed.is_valid() {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    /// Whether this is a real date from 1970 on.
+    pub fn is_valid(&self) -> bool {
+        let leap = self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0);
+        let days_in_month = match self.month {
+            2 if leap => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && (1..=days_in_month).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Converts seconds since the Unix epoch, using the days-to-civil
+    /// algorithm from Howard Hinnant's date library.
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let days = seconds / 86400;
+        let rest = seconds % 86400;
+        // Shift the epoch to 0000-03-01 so leap days fall at the end of a year.
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+        DateTime {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (rest / 3600) as u8,
+            minute: (rest / 60 % 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+
+    /// Seconds since the Unix epoch. Dates before 1970 clamp to 0.
+    pub fn to_unix(&self) -> u64 {
+        let month = u64::from(self.month);
+        let year = u64::from(self.year).saturating_sub(u64::from(month <= 2));
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month_index = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month_index + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+        days * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+pub fn date(args: &[&str]) {
+    match args {
+        [] => match now() {
+            Some(now) => println!("{}", now),
+            None => println!("date: The clock is not set."),
+        },
+        ["-s", date, time] => match DateTime::parse(date, time) {
+            Some(time) => {
+                set_millis(time.to_unix() * 1000);
+                println!("{}", time);
+            }
+            None => println!("date: Invalid date '{} {}'.", date, time),
+        },
+        _ => {
+            println!("Usage: date [-s YYYY-MM-DD HH:MM[:SS]]");
+            println!("  -s  set the system clock, in UTC; hwclock -w saves it to the RTC");
+        }
+    }
+}
+
+#[test_case]
+fn test_date_from_unix() {
+    let epoch = DateTime::from_unix(0);
+    assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+    let date = DateTime::from_unix(1_700_000_000);
+    assert_eq!(
+        (
+            date.year,
+            date.month,
+            date.day,
+            date.hour,
+            date.minute,
+            date.second
+        ),
+        (2023, 11, 14, 22, 13, 20)
+    );
+    let leap_day = DateTime::from_unix(951_782_400);
+    assert_eq!((leap_day.year, leap_day.month, leap_day.day), (2000, 2, 29));
+}
+
+#[test_case]
+fn test_date_round_trip() {
+    for seconds in [0, 951_782_400, 1_700_000_000, 4_102_444_799] {
+        assert_eq!(DateTime::from_unix(seconds).to_unix(), seconds);
+    }
+}
+
+#[test_case]
+fn test_date_parse() {
+    assert_eq!(
+        DateTime::parse("2024-02-29", "13:05"),
+        Some(DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 5,
+            second: 0
+        })
+    );
+    assert_eq!(DateTime::parse("2023-02-29", "13:05:00"), None);
+    assert_eq!(DateTime::parse("2024-01-01", "24:00"), None);
+    assert_eq!(DateTime::parse("2024-01", "12:00"), None);
+}
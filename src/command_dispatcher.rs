@@ -1,65 +1,102 @@
 use core::arch::global_asm;
 
 use crate::{
+    acpi,
     assembler::*,
     basic_commands::white_space_divider,
-    interrupts::{acpi_shutdown, input},
-    mem_filesystem::FileSystem,
-    println,
+    clock, disk, exceptions,
+    interrupts::{self, input},
+    mem_filesystem::FILESYSTEM,
+    net, pci, pit, print, println, request, rtc, vfs,
+    vga_buffer::capture,
 };
-use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
 use lazy_static::lazy_static;
 
 use spin::Mutex;
 
+type Command = fn(&[&str]);
+
 lazy_static! {
-    static ref COMMANDS: Mutex<BTreeMap<&'static str, fn()>> = {
+    static ref COMMANDS: Mutex<BTreeMap<&'static str, Command>> = {
         let mut m = BTreeMap::new();
-        m.insert("shutdown", shutdown as fn());
-        m.insert("name", welcome as fn());
-        m.insert("welcome", welcome as fn());
-        m.insert("touch", create_file as fn());
-        m.insert("help", help as fn());
-        m.insert("micro", open_file as fn());
-        m.insert("clear", clear as fn());
-        m.insert("kas", kas as fn());
-        m.insert("ras", run_assembly as fn());
+        m.insert("shutdown", shutdown as Command);
+        m.insert("reboot", reboot as Command);
+        m.insert("name", welcome as Command);
+        m.insert("welcome", welcome as Command);
+        m.insert("touch", create_file as Command);
+        m.insert("help", help as Command);
+        m.insert("micro", open_file as Command);
+        m.insert("clear", clear as Command);
+        m.insert("kas", kas as Command);
+        m.insert("ras", run_assembly as Command);
+        m.insert("echo", echo as Command);
+        m.insert("uptime", uptime as Command);
+        m.insert("date", clock::date as Command);
+        m.insert("hwclock", rtc::hwclock as Command);
+        m.insert("ls", ls as Command);
+        m.insert("cat", cat as Command);
+        m.insert("cp", cp as Command);
+        m.insert("lsblk", disk::lsblk as Command);
+        m.insert("bgcopy", disk::bgcopy as Command);
+        m.insert("lspci", pci::lspci as Command);
+        m.insert("ifconfig", net::ifconfig as Command);
+        m.insert("route", net::route as Command);
+        m.insert("arp", net::arp as Command);
+        m.insert("pcap", net::pcap as Command);
+        m.insert("ping", net::ping as Command);
+        m.insert("nslookup", net::nslookup as Command);
+        m.insert("ntpdate", net::ntpdate as Command);
+        m.insert("telnetd", net::telnetd as Command);
+        m.insert("tftp", net::tftp as Command);
+        m.insert("fetch", request::fetch as Command);
+        m.insert("httpd", net::httpd as Command);
         Mutex::new(m)
     };
-    static ref FILESYSTEM: Mutex<FileSystem> = Mutex::new(FileSystem::new(1024, 128, 512));
     static ref FILES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 }
 
-fn help() {
+fn help(_args: &[&str]) {
     white_space_divider(5);
     println!("======================== KukiOS HELP center ========================");
     println!("Hello, This is KukiOS. \n We're happy to see you join our community. \n We are developing, a free linux-like system, entirely in Rust and x86_64 Assembly. \n We love Linux, Mac, but hate Windows. \n This is just a free, non-opensource project. \n --- Kukiweb.cz and KukiOS Admin, Kuki202");
     println!("====================================================================");
 }
 
-fn clear() {
+fn clear(_args: &[&str]) {
     let _whitespaces = white_space_divider(40);
 }
 
-fn welcome() {
+fn welcome(_args: &[&str]) {
     let name = input();
     println!("Hello, {name}!")
 }
 
-fn shutdown() {
+fn shutdown(_args: &[&str]) {
     println!("Shutting down.");
-    unsafe { acpi_shutdown() }
+    if let Err(err) = acpi::shutdown() {
+        println!("shutdown: {}", err);
+    }
 }
 
-fn create_file() {
-    let mut fs = FILESYSTEM.lock();
-    let mut _files = FILES.lock();
+fn reboot(_args: &[&str]) {
+    println!("Rebooting.");
+    if let Err(err) = acpi::reboot() {
+        println!("reboot: {}", err);
+    }
+}
+
+fn create_file(_args: &[&str]) {
     white_space_divider(1);
     println!("Enter file name: ");
     let file_name = input();
     white_space_divider(1);
     println!("Enter file's text: ");
     let content = input();
+    // Only now: input may wait for a remote user, and nothing else can get
+    // the locks meanwhile.
+    let mut fs = FILESYSTEM.lock();
+    let mut _files = FILES.lock();
     let x = fs.create_file(1024, file_name.as_str()).unwrap();
     fs.write_file(x, content.trim().as_bytes());
     white_space_divider(1);
@@ -68,17 +105,18 @@ fn create_file() {
     // inodes.push(x);
 }
 
-fn run_assembly() {
-    let mut fs = FILESYSTEM.lock();
-    let mut buffer = vec![0; 1024];
-    let file_name = input();
-    if let Some(size) = fs.read_file_by_name(&file_name, &mut buffer) {
-        buffer.truncate(size);
-        // Some(buffer)
-    } else {
-        // None
-        println!("ERROR: ASM Buffer not found.")
-    }
+fn run_assembly(args: &[&str]) {
+    let file_name = match args.first() {
+        Some(name) => String::from(*name),
+        None => input(),
+    };
+    let buffer = match vfs::read(&file_name, None) {
+        Ok(buffer) => buffer,
+        Err(_) => {
+            println!("ERROR: ASM Buffer not found.");
+            return;
+        }
+    };
     let entry_point = buffer.as_ptr() as usize;
     unsafe {
         let func: extern "C" fn() -> ! = core::mem::transmute(entry_point);
@@ -86,16 +124,19 @@ fn run_assembly() {
     }
 }
 
-fn open_file() {
-    let fs = FILESYSTEM.lock();
-    let mut buffer = vec![0u8; 1024];
+fn open_file(args: &[&str]) {
+    let file_name = match args.first() {
+        Some(name) => String::from(*name),
+        None => {
+            white_space_divider(1);
+            println!("Enter file name to open: ");
+            input()
+        }
+    };
     let mut _files = FILES.lock();
-    white_space_divider(1);
-    println!("Enter file name to open: ");
-    let file_name = input();
     println!("Opening {file_name}....");
-    if let Some(bytes_read) = fs.read_file_by_name(file_name.as_str(), &mut buffer) {
-        let data = core::str::from_utf8(&buffer[..bytes_read]).unwrap();
+    if let Ok(buffer) = vfs::read(file_name.as_str(), Some(1024)) {
+        let data = String::from_utf8_lossy(&buffer);
         println!("====================== FILE {file_name} (read-only) ======================");
         println!("{}", data.replace(" ", "").trim().trim_end()); //.replace(" ", "").trim()
         println!("==============================================================");
@@ -111,13 +152,37 @@ fn open_file() {
 }
 
 pub fn dispatch_command(cmd: &str) {
-    let commands = COMMANDS.lock();
-    if let Some(&command_fn) = commands.get(cmd) {
-        command_fn();
-    } else {
-        white_space_divider(1);
-        println!("KukiOS command center: Unknown command: >>> {cmd} <<<");
-        white_space_divider(1);
+    let (cmd, redirect) = match cmd.split_once('>') {
+        Some((cmd, target)) => (cmd, Some(target.trim())),
+        None => (cmd, None),
+    };
+    let mut parts = cmd.split_whitespace();
+    let name = parts.next().unwrap_or("");
+    let args: Vec<&str> = parts.collect();
+    let command = COMMANDS.lock().get(name).copied();
+    let command_fn = match command {
+        Some(command_fn) => command_fn,
+        None => {
+            white_space_divider(1);
+            println!("KukiOS command center: Unknown command: >>> {name} <<<");
+            white_space_divider(1);
+            return;
+        }
+    };
+    // A command that faults is terminated and the shell carries on.
+    let result = match redirect {
+        Some(target) => {
+            let mut result = Ok(());
+            let output = capture(|| result = exceptions::catch(|| command_fn(&args)));
+            if let Err(err) = vfs::write(target, output.as_bytes()) {
+                println!("Cannot write to {target}: {err}");
+            }
+            result
+        }
+        None => exceptions::catch(|| command_fn(&args)),
+    };
+    if let Err(fault) = result {
+        println!("{name}: terminated by {fault}");
     }
 }
 
@@ -125,16 +190,73 @@ pub fn uname() {
     println!("KukiOS: 0.1.0");
 }
 
-fn echo() {
-    println!("");
-    println!("What to echo?");
-    let echo = input();
-    println!("");
-    println!("Where to echo? (file-which, here)");
-    let whereto = input();
+fn echo(args: &[&str]) {
+    println!("{}", args.join(" "));
+}
+
+fn uptime(_args: &[&str]) {
+    let seconds = interrupts::uptime().as_secs();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    println!(
+        "up {} days, {:02}:{:02}:{:02} ({} ticks at {} Hz)",
+        days,
+        hours,
+        minutes,
+        seconds % 60,
+        interrupts::ticks(),
+        pit::TIMER_HZ
+    );
+}
+
+fn ls(args: &[&str]) {
+    let path = args.first().copied().unwrap_or("/");
+    match vfs::list(path) {
+        Ok(entries) => {
+            for entry in entries {
+                println!("{entry}");
+            }
+        }
+        Err(err) => println!("ls: {path}: {err}"),
+    }
+}
+
+fn parse_limit(arg: Option<&&str>) -> Result<Option<usize>, ()> {
+    match arg {
+        Some(limit) => limit.parse().map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+fn cat(args: &[&str]) {
+    let (path, limit) = match (args.first(), parse_limit(args.get(1))) {
+        (Some(path), Ok(limit)) => (*path, limit),
+        _ => {
+            println!("Usage: cat <file> [bytes]");
+            return;
+        }
+    };
+    match vfs::read(path, limit) {
+        Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+        Err(err) => println!("cat: {path}: {err}"),
+    }
+}
+
+fn cp(args: &[&str]) {
+    let (source, target, limit) = match (args.get(0), args.get(1), parse_limit(args.get(2))) {
+        (Some(source), Some(target), Ok(limit)) => (*source, *target, limit),
+        _ => {
+            println!("Usage: cp <source> <target> [bytes]");
+            return;
+        }
+    };
+    let result = vfs::read(source, limit).and_then(|data| vfs::write(target, &data));
+    match result {
+        Ok(count) => println!("Copied {count} bytes from {source} to {target}."),
+        Err(err) => println!("cp: {err}"),
+    }
 }
 
-fn kas() {
+fn kas(_args: &[&str]) {
     let mut msg = b"";
     let mut g: &str = "";
 
@@ -0,0 +1,200 @@
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    disk::{Disk, SECTOR_SIZE},
+    interrupts::read_line,
+    serial::SERIAL1,
+    vga_buffer::WRITER,
+};
+
+/// A device node under `/dev`. Reads and writes work on byte offsets, like a file.
+pub trait Device: Sync {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str>;
+    fn write(&self, offset: usize, data: &[u8]) -> Result<usize, &'static str>;
+    /// Size in bytes, `None` for endless streams like `/dev/zero`. Block
+    /// devices report `None` too, so a read without a limit can't pull a
+    /// whole disk into memory; such reads fail instead. `lsblk` shows their
+    /// size.
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+static DEVICES: [(&str, &dyn Device); 6] = [
+    ("null", &Null),
+    ("zero", &Zero),
+    ("serial0", &Serial),
+    ("console", &Console),
+    ("hda", &Hda),
+    ("random", &Random),
+];
+
+pub fn lookup(name: &str) -> Option<&'static dyn Device> {
+    DEVICES
+        .iter()
+        .find(|(device_name, _)| *device_name == name)
+        .map(|(_, device)| *device)
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    DEVICES.iter().map(|(name, _)| *name)
+}
+
+struct Null;
+
+impl Device for Null {
+    fn read(&self, _offset: usize, _buffer: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(0)
+    }
+    fn write(&self, _offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(data.len())
+    }
+    fn size(&self) -> Option<usize> {
+        Some(0)
+    }
+}
+
+struct Zero;
+
+impl Device for Zero {
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+    fn write(&self, _offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        Ok(data.len())
+    }
+}
+
+struct Serial;
+
+const SERIAL_DATA_PORT: u16 = 0x3F8;
+const SERIAL_LINE_STATUS_PORT: u16 = 0x3FD;
+
+impl Device for Serial {
+    /// Returns whatever already arrived on COM1 without waiting for more.
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        interrupts::without_interrupts(|| {
+            let _serial = SERIAL1.lock();
+            let mut line_status = Port::<u8>::new(SERIAL_LINE_STATUS_PORT);
+            let mut data = Port::<u8>::new(SERIAL_DATA_PORT);
+            let mut count = 0;
+            while count < buffer.len() && unsafe { line_status.read() } & 1 != 0 {
+                buffer[count] = unsafe { data.read() };
+                count += 1;
+            }
+            Ok(count)
+        })
+    }
+    fn write(&self, _offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.lock();
+            for &byte in data {
+                serial.send(byte);
+            }
+        });
+        Ok(data.len())
+    }
+}
+
+struct Console;
+
+impl Device for Console {
+    /// Reads one line typed on the keyboard.
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let line = read_line();
+        let count = usize::min(line.len(), buffer.len());
+        buffer[..count].copy_from_slice(&line.as_bytes()[..count]);
+        Ok(count)
+    }
+    fn write(&self, _offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let text = core::str::from_utf8(data).map_err(|_| "Console only accepts UTF-8 text.")?;
+        interrupts::without_interrupts(|| {
+            WRITER
+                .lock()
+                .write_str(text)
+                .map_err(|_| "Writing to the console failed.")
+        })?;
+        Ok(data.len())
+    }
+}
+
+struct Hda;
+
+impl Device for Hda {
+    fn read(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let mut sector_buffer = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done;
+            let sector_offset = position % SECTOR_SIZE;
+            Disk.read_sector((position / SECTOR_SIZE) as u64, &mut sector_buffer)?;
+            let count = usize::min(SECTOR_SIZE - sector_offset, buffer.len() - done);
+            buffer[done..done + count]
+                .copy_from_slice(&sector_buffer[sector_offset..sector_offset + count]);
+            done += count;
+        }
+        Ok(done)
+    }
+    fn write(&self, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let mut sector_buffer = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done;
+            let sector = (position / SECTOR_SIZE) as u64;
+            let sector_offset = position % SECTOR_SIZE;
+            let count = usize::min(SECTOR_SIZE - sector_offset, data.len() - done);
+            if count < SECTOR_SIZE {
+                Disk.read_sector(sector, &mut sector_buffer)?;
+            }
+            sector_buffer[sector_offset..sector_offset + count]
+                .copy_from_slice(&data[done..done + count]);
+            Disk.write_sector(sector, &sector_buffer)?;
+            done += count;
+        }
+        Ok(done)
+    }
+}
+
+struct Random;
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+impl Random {
+    /// xorshift64*, seeded from the time stamp counter on first use.
+    fn next(&self) -> u64 {
+        let mut state = RANDOM_STATE.load(Ordering::Relaxed);
+        if state == 0 {
+            state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+        }
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        RANDOM_STATE.store(state, Ordering::Relaxed);
+        state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Device for Random {
+    fn read(&self, _offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        for chunk in buffer.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(buffer.len())
+    }
+    /// Written bytes are mixed into the generator state.
+    fn write(&self, _offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        for chunk in data.chunks(8) {
+            let mut bytes = [0u8; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            RANDOM_STATE.fetch_xor(u64::from_le_bytes(bytes), Ordering::Relaxed);
+        }
+        Ok(data.len())
+    }
+}
@@ -1,111 +1,714 @@
-use core::arch::asm;
+use core::{
+    arch::asm,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
 
+use alloc::{collections::VecDeque, string::String, vec, vec::Vec};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::instructions::port::Port;
 
-use crate::println;
+use crate::{
+    interrupts::{enable_irq, uptime_millis},
+    println, task,
+};
 
 pub const SECTOR_SIZE: usize = 512;
 
-pub struct Disk;
+/// How long a drive may stay busy before a command is given up on.
+const ATA_TIMEOUT_MS: u64 = 2000;
+/// Upper bound on status polls, so a wait ends even with interrupts off.
+const ATA_MAX_POLLS: u32 = 10_000_000;
+/// Sectors moved per command. 0 in the count register means 256 on LBA28.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+const LBA28_LIMIT: u64 = 1 << 28;
 
-impl Disk {
-    pub fn read_sector(
-        &self,
-        sector: u64,
-        buffer: &mut [u8; SECTOR_SIZE],
-    ) -> Result<(), &'static str> {
-        println!("[STATUS] Started to read the sector!");
-        assert!(buffer.len() == SECTOR_SIZE);
-        unsafe {
-            Port::new(0x1F2).write(1_u8);
-            Port::new(0x1F3).write((sector & 0xFF) as u8);
-            Port::new(0x1F4).write(((sector >> 8) & 0xFF) as u8);
-            Port::new(0x1F5).write(((sector >> 16) & 0xFF) as u8);
-            Port::new(0x1F6).write(0xE0 | (((sector >> 24) & 0x0F) as u8));
-            Port::new(0x1F7).write(0x20_u8);
-        }
-        self.wait_for_io();
-        for i in 0..(SECTOR_SIZE / 2) {
-            let data: u16 = unsafe { Port::new(0x1F0).read() };
-            buffer[i * 2] = (data & 0xFF) as u8;
-            buffer[i * 2 + 1] = (data >> 8) as u8;
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    NoDevice,
+    NotAta,
+    Timeout,
+    DeviceFault,
+    /// The drive set ERR; the value is its error register.
+    Command(u8),
+    OutOfRange,
+    BadBuffer,
+    /// An asynchronous transfer currently owns the channel.
+    Busy,
+}
+
+impl AtaError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AtaError::NoDevice => "no drive attached",
+            AtaError::NotAta => "not an ATA drive",
+            AtaError::Timeout => "drive timed out",
+            AtaError::DeviceFault => "drive fault",
+            AtaError::Command(_) => "drive reported an error",
+            AtaError::OutOfRange => "sector out of range",
+            AtaError::BadBuffer => "buffer is not a whole number of sectors",
+            AtaError::Busy => "channel busy with another transfer",
+        }
+    }
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtaError::Command(error) => write!(f, "{} (error {:#04X})", self.as_str(), error),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl From<AtaError> for &'static str {
+    fn from(error: AtaError) -> Self {
+        error.as_str()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+impl Channel {
+    fn index(self) -> usize {
+        match self {
+            Channel::Primary => 0,
+            Channel::Secondary => 1,
+        }
+    }
+    fn irq(self) -> u8 {
+        match self {
+            Channel::Primary => 14,
+            Channel::Secondary => 15,
+        }
+    }
+    fn io_base(self) -> u16 {
+        match self {
+            Channel::Primary => 0x1F0,
+            Channel::Secondary => 0x170,
+        }
+    }
+    fn control_base(self) -> u16 {
+        match self {
+            Channel::Primary => 0x3F6,
+            Channel::Secondary => 0x376,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AtaDrive {
+    pub channel: Channel,
+    pub slave: bool,
+    pub model: String,
+    pub sectors: u64,
+    pub lba48: bool,
+}
+
+lazy_static! {
+    static ref DRIVES: Mutex<Vec<AtaDrive>> = Mutex::new(Vec::new());
+    /// Tasks waiting for a busy channel, woken when it is freed.
+    static ref CHANNEL_WAITERS: [Mutex<VecDeque<Waker>>; 2] =
+        [Mutex::new(VecDeque::new()), Mutex::new(VecDeque::new())];
+}
+
+static IRQ_FIRED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+static IRQ_WAKERS: [AtomicWaker; 2] = [AtomicWaker::new(), AtomicWaker::new()];
+static CHANNEL_BUSY: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// Probes all four ATA positions and remembers the drives that answer IDENTIFY.
+pub fn init() {
+    let mut drives = DRIVES.lock();
+    drives.clear();
+    for &channel in &[Channel::Primary, Channel::Secondary] {
+        // Clear nIEN so the drives raise IRQ14/15 when a sector is ready.
+        unsafe { Port::<u8>::new(channel.control_base()).write(0) };
+        enable_irq(channel.irq());
+        for &slave in &[false, true] {
+            match AtaDrive::identify(channel, slave) {
+                Ok(drive) => {
+                    println!(
+                        "[OK] ATA {:?} {}: {} ({} MiB)",
+                        channel,
+                        if slave { "slave" } else { "master" },
+                        drive.model,
+                        drive.sectors * SECTOR_SIZE as u64 / (1024 * 1024)
+                    );
+                    drives.push(drive);
+                }
+                Err(AtaError::NoDevice) | Err(AtaError::NotAta) => {}
+                Err(err) => println!("[INFO] ATA {:?} probe failed: {}", channel, err),
+            }
+        }
+    }
+}
+
+pub fn drives() -> Vec<AtaDrive> {
+    DRIVES.lock().clone()
+}
+
+/// Device name of a drive's ATA position, in the Linux `hdX` scheme.
+pub fn drive_name(drive: &AtaDrive) -> &'static str {
+    match (drive.channel, drive.slave) {
+        (Channel::Primary, false) => "hda",
+        (Channel::Primary, true) => "hdb",
+        (Channel::Secondary, false) => "hdc",
+        (Channel::Secondary, true) => "hdd",
+    }
+}
+
+struct Registers {
+    data: Port<u16>,
+    error: Port<u8>,
+    sector_count: Port<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_head: Port<u8>,
+    command: Port<u8>,
+    alt_status: Port<u8>,
+}
+
+impl Registers {
+    fn new(channel: Channel) -> Self {
+        let base = channel.io_base();
+        Registers {
+            data: Port::new(base),
+            error: Port::new(base + 1),
+            sector_count: Port::new(base + 2),
+            lba_low: Port::new(base + 3),
+            lba_mid: Port::new(base + 4),
+            lba_high: Port::new(base + 5),
+            drive_head: Port::new(base + 6),
+            command: Port::new(base + 7),
+            alt_status: Port::new(channel.control_base()),
+        }
+    }
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+    /// Reading the alternate status four times gives the drive its 400ns.
+    fn delay_400ns(&mut self) {
+        for _ in 0..4 {
+            unsafe { self.alt_status.read() };
+        }
+    }
+    fn wait_not_busy(&mut self) -> Result<u8, AtaError> {
+        let deadline = uptime_millis() + ATA_TIMEOUT_MS;
+        for _ in 0..ATA_MAX_POLLS {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if uptime_millis() > deadline {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Err(AtaError::Timeout)
+    }
+    /// Waits until the drive wants data moved, failing on ERR or DF.
+    fn wait_data_request(&mut self) -> Result<(), AtaError> {
+        let deadline = uptime_millis() + ATA_TIMEOUT_MS;
+        for _ in 0..ATA_MAX_POLLS {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                if status & STATUS_ERR != 0 {
+                    return Err(AtaError::Command(unsafe { self.error.read() }));
+                }
+                if status & STATUS_DF != 0 {
+                    return Err(AtaError::DeviceFault);
+                }
+                if status & STATUS_DRQ != 0 {
+                    return Ok(());
+                }
+            }
+            if uptime_millis() > deadline {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Err(AtaError::Timeout)
+    }
+    fn check_completion(&mut self) -> Result<(), AtaError> {
+        let status = self.wait_not_busy()?;
+        if status & STATUS_ERR != 0 {
+            return Err(AtaError::Command(unsafe { self.error.read() }));
+        }
+        if status & STATUS_DF != 0 {
+            return Err(AtaError::DeviceFault);
         }
         Ok(())
     }
-    pub fn write_sector(
-        &self,
-        sector: u64,
-        buffer: &[u8; SECTOR_SIZE],
-    ) -> Result<(), &'static str> {
-        check_stack();
-        println!("[STATUS] Started to write to sector {sector}");
-        assert!(buffer.len() == SECTOR_SIZE);
-        for (i, byte) in buffer.iter().enumerate() {
-            println!("Buffer[{}]: {:#X}", i, byte);
+}
+
+impl AtaDrive {
+    pub fn identify(channel: Channel, slave: bool) -> Result<AtaDrive, AtaError> {
+        let mut regs = Registers::new(channel);
+        unsafe {
+            if regs.status() == 0xFF {
+                // Floating bus, nothing is connected to this channel.
+                return Err(AtaError::NoDevice);
+            }
+            regs.drive_head.write(0xA0 | ((slave as u8) << 4));
+            regs.delay_400ns();
+            regs.sector_count.write(0);
+            regs.lba_low.write(0);
+            regs.lba_mid.write(0);
+            regs.lba_high.write(0);
+            regs.command.write(COMMAND_IDENTIFY);
+        }
+        if regs.status() == 0 {
+            return Err(AtaError::NoDevice);
+        }
+        regs.wait_not_busy()?;
+        if unsafe { regs.lba_mid.read() } != 0 || unsafe { regs.lba_high.read() } != 0 {
+            // ATAPI and SATA bridges put their signature here instead of answering.
+            return Err(AtaError::NotAta);
+        }
+        regs.wait_data_request()?;
+        let mut words = [0u16; 256];
+        for word in words.iter_mut() {
+            *word = unsafe { regs.data.read() };
         }
+
+        let mut model = String::new();
+        for word in &words[27..47] {
+            model.push((word >> 8) as u8 as char);
+            model.push((word & 0xFF) as u8 as char);
+        }
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            u64::from(words[100])
+                | u64::from(words[101]) << 16
+                | u64::from(words[102]) << 32
+                | u64::from(words[103]) << 48
+        } else {
+            u64::from(words[60]) | u64::from(words[61]) << 16
+        };
+        Ok(AtaDrive {
+            channel,
+            slave,
+            model: String::from(model.trim()),
+            sectors,
+            lba48,
+        })
+    }
+
+    fn select(&self, regs: &mut Registers, lba: u64, count: usize, lba48: bool) {
+        let slave_bit = (self.slave as u8) << 4;
         unsafe {
-            println!("[STATUS] Sending sector count to 0x1F2");
-            Port::new(0x1F2).write(1_u8);
-            io_delay();
-
-            println!("[STATUS] Sending sector low byte to 0x1F3");
-            Port::new(0x1F3).write((sector & 0xFF) as u8);
-            io_delay();
-
-            println!("[STATUS] Sending sector middle byte to 0x1F5");
-            Port::new(0x1F4).write(((sector >> 8) & 0xFF) as u8);
-            io_delay();
-
-            println!("[STATUS] Sending sector high byte to 0x1F5");
-            Port::new(0x1F5).write(((sector >> 16) & 0xFF) as u8);
-            io_delay();
-
-            println!("[STATUS] Sending drive/head and LBA mode to 0x1F6");
-            Port::new(0x1F6).write(0xE0 | (((sector >> 24) & 0x0F) as u8));
-            io_delay();
-
-            println!("[STATUS] Sending write command to 0x1F7");
-            Port::new(0x1F7).write(0x30_u8);
-            io_delay();
-        }
-        self.wait_for_io();
-        for i in 0..(SECTOR_SIZE / 2) {
-            let data = u16::from(buffer[i * 2]) | (u16::from(buffer[i * 2 + 1]) << 8);
-            println!("Writing data: {:#X} to port 0x1F0", data);
-            unsafe {
-                Port::new(0x1F0).write(data);
-                io_delay()
+            if lba48 {
+                regs.drive_head.write(0x40 | slave_bit);
+                regs.delay_400ns();
+                regs.sector_count.write((count >> 8) as u8);
+                regs.lba_low.write((lba >> 24) as u8);
+                regs.lba_mid.write((lba >> 32) as u8);
+                regs.lba_high.write((lba >> 40) as u8);
+            } else {
+                regs.drive_head
+                    .write(0xE0 | slave_bit | ((lba >> 24) & 0x0F) as u8);
+                regs.delay_400ns();
+            }
+            regs.sector_count.write(count as u8);
+            regs.lba_low.write(lba as u8);
+            regs.lba_mid.write((lba >> 8) as u8);
+            regs.lba_high.write((lba >> 16) as u8);
+        }
+    }
+
+    fn check_range(&self, lba: u64, buffer_len: usize) -> Result<usize, AtaError> {
+        if buffer_len % SECTOR_SIZE != 0 {
+            return Err(AtaError::BadBuffer);
+        }
+        let count = buffer_len / SECTOR_SIZE;
+        if lba + count as u64 > self.sectors {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(count)
+    }
+
+    /// The polling paths run to completion, so they only have to stay out of
+    /// the way of a transfer that is waiting for its interrupt.
+    fn check_idle(&self) -> Result<(), AtaError> {
+        if CHANNEL_BUSY[self.channel.index()].load(Ordering::Acquire) {
+            return Err(AtaError::Busy);
+        }
+        Ok(())
+    }
+
+    fn needs_lba48(&self, lba: u64, count: usize) -> Result<bool, AtaError> {
+        let lba48 = lba + count as u64 > LBA28_LIMIT;
+        if lba48 && !self.lba48 {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(lba48)
+    }
+
+    /// Reads whole sectors starting at `lba`; `buffer` decides how many.
+    pub fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buffer.len())?;
+        self.check_idle()?;
+        let mut regs = Registers::new(self.channel);
+        for (i, chunk) in buffer
+            .chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.needs_lba48(chunk_lba, count)?;
+            regs.wait_not_busy()?;
+            self.select(&mut regs, chunk_lba, count, lba48);
+            let command = if lba48 {
+                COMMAND_READ_SECTORS_EXT
+            } else {
+                COMMAND_READ_SECTORS
             };
+            unsafe { regs.command.write(command) };
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                regs.delay_400ns();
+                regs.wait_data_request()?;
+                for pair in sector.chunks_mut(2) {
+                    let data: u16 = unsafe { regs.data.read() };
+                    pair[0] = (data & 0xFF) as u8;
+                    pair[1] = (data >> 8) as u8;
+                }
+            }
         }
-        println!("[OK] Finished writing to sector {}", sector);
-        io_delay();
-        io_delay();
-        println!("[WAIT] Still working....");
-        io_delay();
-        io_delay();
-        println!("[OK] WORKING!");
-        check_stack();
         Ok(())
     }
-    fn wait_for_io(&self) {
-        loop {
-            println!("[WAIT] Waiting for io....");
-            let status: u8 = unsafe { Port::new(0x1F7).read() };
-            println!("[INFO] IO status: {:#X}", status);
-            if status & 0x08 != 0 {
-                println!("[OK] IO is ready!");
-                break;
+
+    /// Writes whole sectors starting at `lba` and flushes the drive's cache.
+    pub fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buffer.len())?;
+        self.check_idle()?;
+        let mut regs = Registers::new(self.channel);
+        let mut any_lba48 = false;
+        for (i, chunk) in buffer
+            .chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.needs_lba48(chunk_lba, count)?;
+            any_lba48 |= lba48;
+            regs.wait_not_busy()?;
+            self.select(&mut regs, chunk_lba, count, lba48);
+            let command = if lba48 {
+                COMMAND_WRITE_SECTORS_EXT
+            } else {
+                COMMAND_WRITE_SECTORS
+            };
+            unsafe { regs.command.write(command) };
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                regs.delay_400ns();
+                regs.wait_data_request()?;
+                for pair in sector.chunks(2) {
+                    let data = u16::from(pair[0]) | (u16::from(pair[1]) << 8);
+                    unsafe { regs.data.write(data) };
+                }
             }
+            regs.check_completion()?;
+        }
+        self.flush(&mut regs, any_lba48)
+    }
+
+    fn start_flush(&self, regs: &mut Registers, lba48: bool) {
+        let command = if lba48 {
+            COMMAND_CACHE_FLUSH_EXT
+        } else {
+            COMMAND_CACHE_FLUSH
+        };
+        unsafe {
+            regs.drive_head.write(0xA0 | ((self.slave as u8) << 4));
+            regs.delay_400ns();
+            regs.command.write(command);
         }
+        regs.delay_400ns();
+    }
+
+    fn flush(&self, regs: &mut Registers, lba48: bool) -> Result<(), AtaError> {
+        self.start_flush(regs, lba48);
+        regs.check_completion()
     }
 }
 
-fn io_delay() {
-    println!("Delaying IO!");
-    unsafe {
-        core::arch::asm!("out 0x80, al", out("al") _, options(nomem, nostack, preserves_flags));
+/// Called from the IRQ14/IRQ15 handlers.
+pub fn handle_interrupt(channel: Channel) {
+    // Reading the status register acknowledges the interrupt on the drive.
+    let _status = Registers::new(channel).status();
+    IRQ_FIRED[channel.index()].store(true, Ordering::Release);
+    IRQ_WAKERS[channel.index()].wake();
+}
+
+/// Resolves on the next interrupt from `channel`.
+struct InterruptFuture {
+    channel: Channel,
+}
+
+impl Future for InterruptFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let index = self.channel.index();
+        if IRQ_FIRED[index].swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+        IRQ_WAKERS[index].register(cx.waker());
+        if IRQ_FIRED[index].swap(false, Ordering::AcqRel) {
+            IRQ_WAKERS[index].take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Waits for the next interrupt from `channel`, for at most [`ATA_TIMEOUT_MS`].
+async fn next_interrupt(channel: Channel) -> Result<(), AtaError> {
+    task::timeout(
+        Duration::from_millis(ATA_TIMEOUT_MS),
+        InterruptFuture { channel },
+    )
+    .await
+    .map_err(|_| AtaError::Timeout)
+}
+
+/// Ownership of a channel for one asynchronous transfer. Dropping it frees
+/// the channel, also when the transfer fails or times out.
+struct ChannelGuard {
+    channel: Channel,
+}
+
+impl Drop for ChannelGuard {
+    fn drop(&mut self) {
+        let index = self.channel.index();
+        CHANNEL_BUSY[index].store(false, Ordering::Release);
+        // All of them: a waiter that was dropped meanwhile can't pass it on.
+        let waiters = core::mem::take(&mut *CHANNEL_WAITERS[index].lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+struct ChannelLock {
+    channel: Channel,
+}
+
+impl Future for ChannelLock {
+    type Output = ChannelGuard;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<ChannelGuard> {
+        let index = self.channel.index();
+        let take = || {
+            CHANNEL_BUSY[index]
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        };
+        if !take() {
+            CHANNEL_WAITERS[index].lock().push_back(cx.waker().clone());
+            // The owner may have let go before we were queued.
+            if !take() {
+                return Poll::Pending;
+            }
+        }
+        Poll::Ready(ChannelGuard {
+            channel: self.channel,
+        })
+    }
+}
+
+fn lock_channel(channel: Channel) -> ChannelLock {
+    ChannelLock { channel }
+}
+
+/// Reads whole sectors, sleeping on the drive's IRQ instead of polling for each one.
+pub async fn read_sectors(drive: &AtaDrive, lba: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+    drive.check_range(lba, buffer.len())?;
+    let _guard = lock_channel(drive.channel).await;
+    let mut regs = Registers::new(drive.channel);
+    for (i, chunk) in buffer
+        .chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+        .enumerate()
+    {
+        let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+        let count = chunk.len() / SECTOR_SIZE;
+        let lba48 = drive.needs_lba48(chunk_lba, count)?;
+        regs.wait_not_busy()?;
+        drive.select(&mut regs, chunk_lba, count, lba48);
+        let command = if lba48 {
+            COMMAND_READ_SECTORS_EXT
+        } else {
+            COMMAND_READ_SECTORS
+        };
+        IRQ_FIRED[drive.channel.index()].store(false, Ordering::Release);
+        unsafe { regs.command.write(command) };
+        for sector in chunk.chunks_mut(SECTOR_SIZE) {
+            next_interrupt(drive.channel).await?;
+            regs.wait_data_request()?;
+            for pair in sector.chunks_mut(2) {
+                let data: u16 = unsafe { regs.data.read() };
+                pair[0] = (data & 0xFF) as u8;
+                pair[1] = (data >> 8) as u8;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes whole sectors and flushes the cache, sleeping on the drive's IRQ in between.
+pub async fn write_sectors(drive: &AtaDrive, lba: u64, buffer: &[u8]) -> Result<(), AtaError> {
+    drive.check_range(lba, buffer.len())?;
+    let _guard = lock_channel(drive.channel).await;
+    let mut regs = Registers::new(drive.channel);
+    let mut any_lba48 = false;
+    for (i, chunk) in buffer
+        .chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+        .enumerate()
+    {
+        let chunk_lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+        let count = chunk.len() / SECTOR_SIZE;
+        let lba48 = drive.needs_lba48(chunk_lba, count)?;
+        any_lba48 |= lba48;
+        regs.wait_not_busy()?;
+        drive.select(&mut regs, chunk_lba, count, lba48);
+        let command = if lba48 {
+            COMMAND_WRITE_SECTORS_EXT
+        } else {
+            COMMAND_WRITE_SECTORS
+        };
+        IRQ_FIRED[drive.channel.index()].store(false, Ordering::Release);
+        unsafe { regs.command.write(command) };
+        // The first sector is requested without an interrupt, every later one
+        // (and the end of the command) is announced by IRQ14/15.
+        regs.wait_data_request()?;
+        for sector in chunk.chunks(SECTOR_SIZE) {
+            for pair in sector.chunks(2) {
+                let data = u16::from(pair[0]) | (u16::from(pair[1]) << 8);
+                unsafe { regs.data.write(data) };
+            }
+            next_interrupt(drive.channel).await?;
+            let status = regs.status();
+            if status & STATUS_ERR != 0 {
+                return Err(AtaError::Command(unsafe { regs.error.read() }));
+            }
+            if status & STATUS_DF != 0 {
+                return Err(AtaError::DeviceFault);
+            }
+        }
+    }
+    IRQ_FIRED[drive.channel.index()].store(false, Ordering::Release);
+    drive.start_flush(&mut regs, any_lba48);
+    next_interrupt(drive.channel).await?;
+    regs.check_completion()
+}
+
+/// Sectors moved per step of a background copy.
+const COPY_CHUNK_SECTORS: u64 = 64;
+
+/// `bgcopy <from> <to> <count>` copies sectors on the boot disk in a background task.
+pub fn bgcopy(args: &[&str]) {
+    let numbers: Vec<u64> = args.iter().filter_map(|arg| arg.parse().ok()).collect();
+    let (from, to, count) = match numbers.as_slice() {
+        [from, to, count] if args.len() == 3 => (*from, *to, *count),
+        _ => {
+            println!("Usage: bgcopy <source-sector> <target-sector> <count>");
+            return;
+        }
+    };
+    let drive = match Disk.drive() {
+        Ok(drive) => drive,
+        Err(err) => {
+            println!("bgcopy: {err}");
+            return;
+        }
+    };
+    println!("[STATUS] Copying {count} sectors from {from} to {to} in the background.");
+    task::spawn(async move {
+        let mut buffer = vec![0u8; COPY_CHUNK_SECTORS as usize * SECTOR_SIZE];
+        let mut done = 0;
+        while done < count {
+            let sectors = u64::min(COPY_CHUNK_SECTORS, count - done);
+            let chunk = &mut buffer[..sectors as usize * SECTOR_SIZE];
+            let result = match read_sectors(&drive, from + done, chunk).await {
+                Ok(()) => write_sectors(&drive, to + done, chunk).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                println!(
+                    "[FAIL] Background copy stopped at sector {}: {err}",
+                    from + done
+                );
+                return;
+            }
+            done += sectors;
+        }
+        println!("[OK] Background copy of {count} sectors finished.");
+    });
+}
+
+/// The boot disk: the first drive found, usually the primary master.
+pub struct Disk;
+
+impl Disk {
+    fn drive(&self) -> Result<AtaDrive, AtaError> {
+        DRIVES.lock().first().cloned().ok_or(AtaError::NoDevice)
+    }
+    pub fn read_sector(&self, sector: u64, buffer: &mut [u8; SECTOR_SIZE]) -> Result<(), AtaError> {
+        self.drive()?.read_sectors(sector, buffer)
+    }
+    pub fn write_sector(&self, sector: u64, buffer: &[u8; SECTOR_SIZE]) -> Result<(), AtaError> {
+        self.drive()?.write_sectors(sector, buffer)
+    }
+}
+
+pub fn lsblk(_args: &[&str]) {
+    let drives = drives();
+    if drives.is_empty() {
+        println!("No ATA drives detected.");
+        return;
+    }
+    println!("NAME  POSITION            SIZE        LBA48  MODEL");
+    for drive in &drives {
+        let position = match (drive.channel, drive.slave) {
+            (Channel::Primary, false) => "primary master",
+            (Channel::Primary, true) => "primary slave",
+            (Channel::Secondary, false) => "secondary master",
+            (Channel::Secondary, true) => "secondary slave",
+        };
+        println!(
+            "{:<5} {:<19} {:>7} MiB  {:<5}  {}",
+            drive_name(drive),
+            position,
+            drive.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+            if drive.lba48 { "yes" } else { "no" },
+            drive.model
+        );
     }
-    println!("IO delayed!")
 }
 
 pub fn check_stack() {
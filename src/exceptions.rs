@@ -0,0 +1,565 @@
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags::RFlags,
+    },
+    structures::idt::{InterruptDescriptorTable, PageFaultErrorCode},
+};
+
+use crate::{
+    backtrace, gdt,
+    interrupts::{count_interrupt, in_irq},
+    serial::SERIAL1,
+    vga_buffer::WRITER,
+};
+
+// Every exception enters through a stub that pushes a dummy error code if the
+// CPU didn't push one, then the vector, then all general purpose registers,
+// so the handler sees the machine state as it was and can change where the
+// `iretq` returns to.
+global_asm!(
+    "
+    .macro exception_stub vector
+    .global exception_stub_\\vector
+    exception_stub_\\vector:
+        push 0
+        push \\vector
+        jmp exception_common
+    .endm
+
+    .macro exception_stub_with_error vector
+    .global exception_stub_\\vector
+    exception_stub_\\vector:
+        push \\vector
+        jmp exception_common
+    .endm
+
+    exception_stub 0
+    exception_stub 1
+    exception_stub 2
+    exception_stub 3
+    exception_stub 4
+    exception_stub 5
+    exception_stub 6
+    exception_stub 7
+    exception_stub_with_error 8
+    exception_stub_with_error 10
+    exception_stub_with_error 11
+    exception_stub_with_error 12
+    exception_stub_with_error 13
+    exception_stub_with_error 14
+    exception_stub 16
+    exception_stub_with_error 17
+    exception_stub 18
+    exception_stub 19
+    exception_stub 20
+    exception_stub_with_error 21
+    exception_stub 28
+    exception_stub_with_error 29
+    exception_stub_with_error 30
+
+    exception_common:
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        mov rdi, rsp
+        cld
+        call exception_handler
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        add rsp, 16
+        iretq
+
+    // rdi = context to fill, rsi = function to call, rdx = its argument.
+    // Returns 0, or 1 when a fault came back through exception_recover.
+    .global exception_catch
+    exception_catch:
+        mov [rdi + 0x00], rbx
+        mov [rdi + 0x08], rbp
+        mov [rdi + 0x10], r12
+        mov [rdi + 0x18], r13
+        mov [rdi + 0x20], r14
+        mov [rdi + 0x28], r15
+        lea rax, [rsp + 8]
+        mov [rdi + 0x30], rax
+        mov rax, [rsp]
+        mov [rdi + 0x38], rax
+        pushfq
+        pop rax
+        mov [rdi + 0x40], rax
+        sub rsp, 8
+        mov rdi, rdx
+        call rsi
+        add rsp, 8
+        xor eax, eax
+        ret
+
+    // Entered with interrupts off and rsp pointing at a context; returns
+    // from the exception_catch call that filled it.
+    .global exception_recover
+    exception_recover:
+        pop rbx
+        pop rbp
+        pop r12
+        pop r13
+        pop r14
+        pop r15
+        pop rax
+        pop rcx
+        pop rdx
+        mov rsp, rax
+        push rdx
+        popfq
+        mov eax, 1
+        jmp rcx
+    "
+);
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_1();
+    fn exception_stub_2();
+    fn exception_stub_3();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_8();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_21();
+    fn exception_stub_28();
+    fn exception_stub_29();
+    fn exception_stub_30();
+    fn exception_catch(context: *mut Context, call: extern "C" fn(*mut u8), data: *mut u8) -> u64;
+    fn exception_recover();
+}
+
+const DEBUG: u8 = 1;
+const NMI: u8 = 2;
+const BREAKPOINT: u8 = 3;
+const DOUBLE_FAULT: u8 = 8;
+const PAGE_FAULT: u8 = 14;
+const MACHINE_CHECK: u8 = 18;
+
+pub fn name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug",
+        2 => "non-maskable interrupt",
+        3 => "breakpoint",
+        4 => "overflow",
+        5 => "bound range exceeded",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        10 => "invalid TSS",
+        11 => "segment not present",
+        12 => "stack-segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating-point exception",
+        17 => "alignment check",
+        18 => "machine check",
+        19 => "SIMD floating-point exception",
+        20 => "virtualization exception",
+        21 => "control protection exception",
+        28 => "hypervisor injection exception",
+        29 => "VMM communication exception",
+        30 => "security exception",
+        _ => "reserved exception",
+    }
+}
+
+/// Installs the stubs for every exception the IDT has an entry for.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // The stubs are plain code addresses, entered the way the CPU enters
+    // an `extern "x86-interrupt"` handler.
+    unsafe fn stub<T>(stub: unsafe extern "C" fn()) -> T {
+        core::mem::transmute_copy(&stub)
+    }
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(exception_stub_0));
+        idt.debug.set_handler_fn(stub(exception_stub_1));
+        idt.non_maskable_interrupt
+            .set_handler_fn(stub(exception_stub_2));
+        idt.breakpoint.set_handler_fn(stub(exception_stub_3));
+        idt.overflow.set_handler_fn(stub(exception_stub_4));
+        idt.bound_range_exceeded
+            .set_handler_fn(stub(exception_stub_5));
+        idt.invalid_opcode.set_handler_fn(stub(exception_stub_6));
+        idt.device_not_available
+            .set_handler_fn(stub(exception_stub_7));
+        idt.double_fault
+            .set_handler_fn(stub(exception_stub_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub(exception_stub_10));
+        idt.segment_not_present
+            .set_handler_fn(stub(exception_stub_11));
+        idt.stack_segment_fault
+            .set_handler_fn(stub(exception_stub_12));
+        idt.general_protection_fault
+            .set_handler_fn(stub(exception_stub_13));
+        idt.page_fault.set_handler_fn(stub(exception_stub_14));
+        idt.x87_floating_point
+            .set_handler_fn(stub(exception_stub_16));
+        idt.alignment_check.set_handler_fn(stub(exception_stub_17));
+        idt.machine_check.set_handler_fn(stub(exception_stub_18));
+        idt.simd_floating_point
+            .set_handler_fn(stub(exception_stub_19));
+        idt.virtualization.set_handler_fn(stub(exception_stub_20));
+        idt.cp_protection_exception
+            .set_handler_fn(stub(exception_stub_21));
+        idt.hv_injection_exception
+            .set_handler_fn(stub(exception_stub_28));
+        idt.vmm_communication_exception
+            .set_handler_fn(stub(exception_stub_29));
+        idt.security_exception
+            .set_handler_fn(stub(exception_stub_30));
+    }
+}
+
+/// The machine state saved by the exception stubs, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// The error code of an exception, decoded as far as its vector says how.
+struct ErrorCode {
+    vector: u8,
+    code: u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.code)?;
+        match self.vector {
+            PAGE_FAULT => {
+                let code = PageFaultErrorCode::from_bits_truncate(self.code);
+                write!(
+                    f,
+                    " ({}, {}, {} mode",
+                    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                        "protection violation"
+                    } else {
+                        "page not present"
+                    },
+                    if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                        "instruction fetch"
+                    } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                        "write"
+                    } else {
+                        "read"
+                    },
+                    if code.contains(PageFaultErrorCode::USER_MODE) {
+                        "user"
+                    } else {
+                        "kernel"
+                    }
+                )?;
+                if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                    write!(f, ", reserved bit set")?;
+                }
+                write!(f, ")")
+            }
+            10..=13 | 17 if self.code != 0 => {
+                let table = match self.code >> 1 & 0b11 {
+                    0 => "GDT",
+                    1 | 3 => "IDT",
+                    _ => "LDT",
+                };
+                write!(f, " ({} index {}", table, self.code >> 3 & 0x1FFF)?;
+                if self.code & 1 != 0 {
+                    write!(f, ", external")?;
+                }
+                write!(f, ")")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Prints a line to the serial port, which still works when the screen is
+/// being captured or scrolled away, and to the screen. Either is skipped when
+/// the code a fault stopped holds it, since waiting would hang forever.
+pub fn report_line(args: fmt::Arguments) {
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        let _ = writeln!(serial, "{}", args);
+    }
+    if let Some(mut writer) = WRITER.try_lock() {
+        let _ = writeln!(writer, "{}", args);
+    }
+}
+
+macro_rules! report {
+    ($($arg:tt)*) => {
+        report_line(format_args!($($arg)*))
+    };
+}
+
+fn dump(registers: &Registers) {
+    let vector = registers.vector as u8;
+    report!(
+        "EXCEPTION: {} (vector {}) at {:#018x}",
+        name(vector),
+        vector,
+        registers.rip
+    );
+    if has_error_code(vector) {
+        report!(
+            "Error code: {}",
+            ErrorCode {
+                vector,
+                code: registers.error_code
+            }
+        );
+    }
+    if vector == PAGE_FAULT {
+        report!("Accessed address (CR2): {:#018x}", Cr2::read().as_u64());
+    }
+    report!(
+        "RAX {:016x} RBX {:016x} RCX {:016x} RDX {:016x}",
+        registers.rax,
+        registers.rbx,
+        registers.rcx,
+        registers.rdx
+    );
+    report!(
+        "RSI {:016x} RDI {:016x} RBP {:016x} RSP {:016x}",
+        registers.rsi,
+        registers.rdi,
+        registers.rbp,
+        registers.rsp
+    );
+    report!(
+        "R8  {:016x} R9  {:016x} R10 {:016x} R11 {:016x}",
+        registers.r8,
+        registers.r9,
+        registers.r10,
+        registers.r11
+    );
+    report!(
+        "R12 {:016x} R13 {:016x} R14 {:016x} R15 {:016x}",
+        registers.r12,
+        registers.r13,
+        registers.r14,
+        registers.r15
+    );
+    report!(
+        "CS {:04x} SS {:04x} RFLAGS {:016x} {:?}",
+        registers.cs,
+        registers.ss,
+        registers.rflags,
+        RFlags::from_bits_truncate(registers.rflags)
+    );
+    let (cr3_frame, _) = Cr3::read();
+    report!(
+        "CR0 {:016x} CR2 {:016x} CR3 {:016x} CR4 {:016x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        cr3_frame.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+    backtrace::print_from(Some(registers.rip), registers.rbp);
+}
+
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// What stopped code run by [`catch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub vector: u8,
+    pub instruction_pointer: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#x}",
+            name(self.vector),
+            self.instruction_pointer
+        )
+    }
+}
+
+/// Callee-saved registers and where to resume, laid out as `exception_catch`
+/// fills them and `exception_recover` pops them. Only the assembly reads it.
+#[allow(dead_code)]
+#[derive(Default)]
+#[repr(C)]
+struct Context {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+    rflags: u64,
+}
+
+/// The innermost [`catch`] running, which a fault returns to.
+static RECOVERY: AtomicPtr<Context> = AtomicPtr::new(ptr::null_mut());
+static LAST_FAULT: Mutex<Option<Fault>> = Mutex::new(None);
+
+/// The innermost [`catch`] of code that isn't running right now, such as a
+/// suspended job, whose `catch` calls are on its own stack.
+pub struct Recovery(*mut Context);
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Recovery(ptr::null_mut())
+    }
+}
+
+/// Makes faults return to the [`catch`] in `recovery` and gives back the
+/// one they returned to until now.
+pub fn swap_recovery(recovery: Recovery) -> Recovery {
+    Recovery(RECOVERY.swap(recovery.0, Ordering::SeqCst))
+}
+
+struct Call<F, R> {
+    f: Option<F>,
+    result: Option<R>,
+}
+
+extern "C" fn call_closure<F: FnOnce() -> R, R>(data: *mut u8) {
+    let call = unsafe { &mut *(data as *mut Call<F, R>) };
+    if let Some(f) = call.f.take() {
+        call.result = Some(f());
+    }
+}
+
+/// Runs `f`, or gives the fault that terminated it. A fault abandons `f`
+/// where it was: nothing it owned is dropped and locks it held stay held, so
+/// this keeps the system running rather than making `f` safe to fail.
+pub fn catch<F: FnOnce() -> R, R>(f: F) -> Result<R, Fault> {
+    let mut context = Context::default();
+    let mut call = Call {
+        f: Some(f),
+        result: None,
+    };
+    let previous = RECOVERY.swap(&mut context, Ordering::SeqCst);
+    let faulted = unsafe {
+        exception_catch(
+            &mut context,
+            call_closure::<F, R>,
+            &mut call as *mut Call<F, R> as *mut u8,
+        )
+    };
+    RECOVERY.store(previous, Ordering::SeqCst);
+    match call.result {
+        Some(result) if faulted == 0 => Ok(result),
+        _ => Err(LAST_FAULT.lock().take().unwrap_or(Fault {
+            vector: 0xFF,
+            instruction_pointer: 0,
+        })),
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_handler(registers: &mut Registers) {
+    let vector = registers.vector as u8;
+    count_interrupt(vector);
+    dump(registers);
+    match vector {
+        // Traps and NMIs report something; the code they stopped may go on.
+        DEBUG | NMI | BREAKPOINT => return,
+        DOUBLE_FAULT | MACHINE_CHECK => panic!("Unrecoverable {}.", name(vector)),
+        _ => {}
+    }
+    // A fault in an IRQ handler that interrupted caught code isn't the caught
+    // code's fault, and returning to its `catch` would leave the handler's
+    // locks and its interrupt unacknowledged.
+    if in_irq() {
+        panic!("{} in an interrupt handler.", name(vector));
+    }
+    let context = RECOVERY.load(Ordering::SeqCst);
+    if context.is_null() {
+        panic!("{} with no task to terminate.", name(vector));
+    }
+    *LAST_FAULT.lock() = Some(Fault {
+        vector,
+        instruction_pointer: registers.rip,
+    });
+    // Return into exception_recover on the caught code's stack, with
+    // interrupts off until it restores the flags `catch` was called with.
+    registers.rip = exception_recover as usize as u64;
+    registers.rsp = context as u64;
+    registers.rflags &= !RFlags::INTERRUPT_FLAG.bits();
+}
+
+#[test_case]
+fn test_catch_invalid_opcode() {
+    let fault = catch(|| unsafe { core::arch::asm!("ud2") }).unwrap_err();
+    assert_eq!(fault.vector, 6);
+    assert_eq!(catch(|| 42), Ok(42));
+}
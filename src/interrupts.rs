@@ -1,24 +1,34 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 #[allow(unused)]
 use crate::{
+    apic, exceptions,
     functions::{_help, _last_two_keys},
-    gdt, hlt_loop, print, println, sleep,
+    gdt, hlt_loop, pit, print, println, sleep,
+    task::job,
 };
 use alloc::{
+    boxed::Box,
     string::{String, ToString},
     vec::Vec,
 };
 
 use conquer_once::spin::Spin;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 use futures_util::future::Lazy;
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 
 use spin::{self, Mutex};
 use x86_64::{
     instructions::port::PortReadOnly,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -26,6 +36,8 @@ use x86_64::{
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
@@ -52,34 +64,217 @@ pub static PICS: spin::Mutex<ChainedPics> =
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        exceptions::install(&mut idt);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
+        for (irq, handler) in DEVICE_IRQ_STUBS.iter() {
+            idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(*handler);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
 }
 
+const ZERO_COUNT: AtomicU64 = AtomicU64::new(0);
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [ZERO_COUNT; 256];
+
+pub(crate) fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// IRQ handlers running right now, counting ones that interrupted others.
+static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Held for as long as an IRQ handler runs.
+struct IrqGuard;
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        IRQ_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts an IRQ and marks its handler as running until the guard drops.
+fn enter_irq(vector: u8) -> IrqGuard {
+    count_interrupt(vector);
+    IRQ_DEPTH.fetch_add(1, Ordering::SeqCst);
+    IrqGuard
+}
+
+/// Whether an IRQ handler is running, so a fault didn't come from the code
+/// it interrupted.
+pub(crate) fn in_irq() -> bool {
+    IRQ_DEPTH.load(Ordering::SeqCst) != 0
+}
+
+/// Vectors that fired at least once, with how often.
+pub fn interrupt_counts() -> impl Iterator<Item = (u8, u64)> {
+    INTERRUPT_COUNTS
+        .iter()
+        .enumerate()
+        .map(|(vector, count)| (vector as u8, count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+}
+
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        v if v < 32 => exceptions::name(v),
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if v == InterruptIndex::PrimaryAta.as_u8() => "ata primary",
+        v if v == InterruptIndex::SecondaryAta.as_u8() => "ata secondary",
+        v if v > PIC_1_OFFSET && v < PIC_2_OFFSET + 8 => "device irq",
+        apic::SPURIOUS_VECTOR => "apic spurious",
+        _ => "unknown",
+    }
+}
+
+/// Timer interrupts since boot, [`pit::TIMER_HZ`] of them a second.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime() -> Duration {
+    let nanos = u128::from(ticks()) * u128::from(pit::DIVISOR) * 1_000_000_000
+        / u128::from(pit::BASE_FREQUENCY);
+    Duration::from_nanos(nanos as u64)
+}
+
+pub fn uptime_millis() -> u64 {
+    ticks() * pit::DIVISOR * 1000 / pit::BASE_FREQUENCY
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     // print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    let _irq = enter_irq(InterruptIndex::Timer.as_u8());
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::net::stack::timer_tick(uptime_millis());
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
+}
+
+/// Acknowledges `vector` to whichever controller delivered it.
+fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
     }
 }
 
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = enter_irq(apic::SPURIOUS_VECTOR);
+}
+
 pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("SERIOUS EXCEPTION! : BREAKPOINT\n{:#?}", stack_frame)
+/// Unmasks a legacy IRQ line (0-15) on the I/O APIC, or on the PICs
+/// including the cascade for 8-15.
+pub fn enable_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if apic::is_enabled() {
+            apic::unmask_irq(irq);
+            return;
+        }
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            master &= !(1 << irq);
+        } else {
+            master &= !(1 << 2);
+            slave &= !(1 << (irq - 8));
+        }
+        unsafe { pics.write_masks(master, slave) };
+    });
+}
+
+/// How many devices can share one PCI interrupt line.
+const HANDLERS_PER_IRQ: usize = 4;
+
+/// Handlers drivers attach to the IRQ lines PCI devices get routed to.
+static DEVICE_IRQ_HANDLERS: Mutex<[[Option<fn()>; HANDLERS_PER_IRQ]; 16]> =
+    Mutex::new([[None; HANDLERS_PER_IRQ]; 16]);
+
+/// Runs `handler` whenever legacy IRQ `irq` fires and unmasks the line.
+/// PCI lines are level-triggered and shared, so every handler on a line runs
+/// and has to check its own device for a pending interrupt.
+pub fn register_irq_handler(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    if !DEVICE_IRQ_STUBS.iter().any(|(line, _)| *line == irq) {
+        return Err("IRQ line is reserved for a built-in device.");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = DEVICE_IRQ_HANDLERS.lock();
+        let slot = handlers[usize::from(irq)]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many handlers on this IRQ line.")?;
+        *slot = Some(handler);
+        Ok(())
+    })?;
+    enable_irq(irq);
+    Ok(())
+}
+
+fn dispatch_device_irq(irq: u8) {
+    let _irq = enter_irq(PIC_1_OFFSET + irq);
+    let handlers = DEVICE_IRQ_HANDLERS.lock()[usize::from(irq)];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+    end_of_interrupt(PIC_1_OFFSET + irq);
+}
+
+macro_rules! device_irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_device_irq($irq);
+        }
+    };
+}
+
+device_irq_handler!(irq3_handler, 3);
+device_irq_handler!(irq4_handler, 4);
+device_irq_handler!(irq5_handler, 5);
+device_irq_handler!(irq6_handler, 6);
+device_irq_handler!(irq7_handler, 7);
+device_irq_handler!(irq8_handler, 8);
+device_irq_handler!(irq9_handler, 9);
+device_irq_handler!(irq10_handler, 10);
+device_irq_handler!(irq11_handler, 11);
+device_irq_handler!(irq12_handler, 12);
+device_irq_handler!(irq13_handler, 13);
+
+static DEVICE_IRQ_STUBS: [(u8, extern "x86-interrupt" fn(InterruptStackFrame)); 11] = [
+    (3, irq3_handler),
+    (4, irq4_handler),
+    (5, irq5_handler),
+    (6, irq6_handler),
+    (7, irq7_handler),
+    (8, irq8_handler),
+    (9, irq9_handler),
+    (10, irq10_handler),
+    (11, irq11_handler),
+    (12, irq12_handler),
+    (13, irq13_handler),
+];
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = enter_irq(InterruptIndex::PrimaryAta.as_u8());
+    crate::disk::handle_interrupt(crate::disk::Channel::Primary);
+    end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _irq = enter_irq(InterruptIndex::SecondaryAta.as_u8());
+    crate::disk::handle_interrupt(crate::disk::Channel::Secondary);
+    end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
 }
 
 #[test_case]
@@ -87,15 +282,9 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("SERIOUS EXCEPTION: DOUBLE FAULT:\n{:#?}", stack_frame);
-}
-
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     // print!("k");
+    let _irq = enter_irq(InterruptIndex::Keyboard.as_u8());
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -113,10 +302,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Keyboard.as_u8());
 
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
@@ -125,6 +311,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
                     let mut buffer = INPUT_BUFFER.lock();
                     if character.to_string() == "\n" {
                         INPUT_READY.store(true, Ordering::SeqCst);
+                        INPUT_WAKER.wake();
                     } else {
                         let _ = character.clone();
                         buffer.push(character);
@@ -152,43 +339,6 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     // if let Some(key) = key {
     //     print!("{}", key)
     // }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
-    }
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    println!(
-        "SERIOUS EXCEPTION: PAGE FAULT (code segment is {})",
-        stack_frame.code_segment
-    );
-    println!("Accessed Address : {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-pub unsafe fn acpi_shutdown() {
-    println!("[INFO] Shutting down in few seconds. Get ready!");
-    use x86_64::instructions::port::Port;
-    println!("[OK - STATUS] Performing shutdown using writing to ACPI control block.");
-    sleep(100000000);
-    const PM1A_CNT_BLK: u16 = 0xB004;
-    const SLP_TYPA: u16 = 0x2000;
-    const SLP_EN: u16 = 1 << 13;
-
-    let shutdown_cmd: u16 = SLP_TYPA | SLP_EN;
-    let mut port = Port::new(PM1A_CNT_BLK);
-
-    port.write(shutdown_cmd);
-    let mut port_604 = Port::new(0x604);
-    port_604.write(0x2000u16);
 }
 
 // fn ask(text: &str) {
@@ -217,14 +367,71 @@ pub unsafe fn acpi_shutdown() {
     }
 }  */
 pub fn input() -> String {
+    print!(">>>> ");
+    read_line()
+}
+
+static INPUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Like [`input`], but lets other tasks run while the user is typing.
+pub async fn input_async() -> String {
     print!(">>>> ");
     {
         let mut buffer = INPUT_BUFFER.lock();
         buffer.clear();
     }
     INPUT_READY.store(false, Ordering::SeqCst);
+    LineFuture.await
+}
+
+struct LineFuture;
+
+impl Future for LineFuture {
+    type Output = String;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<String> {
+        if !INPUT_READY.load(Ordering::SeqCst) {
+            INPUT_WAKER.register(cx.waker());
+            if !INPUT_READY.load(Ordering::SeqCst) {
+                return Poll::Pending;
+            }
+            INPUT_WAKER.take();
+        }
+        let buffer = INPUT_BUFFER.lock();
+        Poll::Ready(buffer.iter().collect())
+    }
+}
+
+/// Supplies input lines in place of the keyboard, e.g. for a remote session.
+pub type LineReader = Box<dyn FnMut() -> String + Send>;
+
+lazy_static! {
+    static ref LINE_READER: Mutex<Option<LineReader>> = Mutex::new(None);
+}
+
+/// Makes [`read_line`] take its lines from `reader` until it is replaced, and
+/// returns the reader it replaces.
+pub fn set_line_reader(reader: Option<LineReader>) -> Option<LineReader> {
+    core::mem::replace(&mut *LINE_READER.lock(), reader)
+}
+
+pub fn read_line() -> String {
+    // Taken out while it runs, since reading blocks and may nest.
+    let reader = LINE_READER.lock().take();
+    if let Some(mut reader) = reader {
+        let line = reader();
+        LINE_READER.lock().get_or_insert(reader);
+        return line;
+    }
+    {
+        let mut buffer = INPUT_BUFFER.lock();
+        buffer.clear();
+    }
+    INPUT_READY.store(false, Ordering::SeqCst);
     while !INPUT_READY.load(Ordering::SeqCst) {
-        x86_64::instructions::hlt();
+        if !job::suspend() {
+            x86_64::instructions::hlt();
+        }
     }
     let buffer = INPUT_BUFFER.lock();
     buffer.iter().collect()
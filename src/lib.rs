@@ -11,30 +11,41 @@ extern crate alloc;
 pub mod allocator;
 // pub mod kukisys;
 // pub mod network;
-// pub mod request;
 // pub mod utils;
 // pub mod functions;
 // pub mod ethernet;
+pub mod acpi;
+pub mod apic;
 pub mod asm;
 pub mod assembler;
+pub mod backtrace;
 pub mod basic_commands;
+pub mod clock;
 pub mod disk;
+pub mod exceptions;
 // pub mod drive_filesystem2;
 pub mod command_dispatcher;
+pub mod devfs;
 pub mod filesystem;
 pub mod functions;
 pub mod gdt;
 pub mod interrupts;
 pub mod mem_filesystem;
 pub mod memory;
+pub mod net;
+pub mod pci;
+pub mod pit;
+pub mod procfs;
 pub mod realsys;
+pub mod request;
+pub mod rtc;
 pub mod serial;
 pub mod task;
+pub mod vfs;
 pub mod vga_buffer;
 
-use core::{arch::asm, panic::PanicInfo};
+use core::panic::PanicInfo;
 
-use alloc::vec::Vec;
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
@@ -54,14 +65,16 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Waits `ms` milliseconds, halting between timer ticks. With interrupts off,
+/// as in a panic, it watches the PIT counter instead.
 pub fn sleep(ms: u64) {
-    let cycles = ms * 1_000_000;
-    let ticks = cycles / (1_000_000_000 / 50);
-    let mut _s: Vec<u64> = Vec::new();
-    for _x in 0..ticks {
-        // println!("Waiting: {x}");
-        // s.push(x);
-        unsafe { asm!("nop") }
+    if !x86_64::instructions::interrupts::are_enabled() {
+        pit::wait_polled(ms);
+        return;
+    }
+    let deadline = interrupts::uptime_millis() + ms;
+    while interrupts::uptime_millis() < deadline {
+        x86_64::instructions::hlt();
     }
 }
 
@@ -69,6 +82,7 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    pit::init();
     x86_64::instructions::interrupts::enable();
 }
 
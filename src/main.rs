@@ -9,15 +9,19 @@ extern crate alloc;
 use alloc::string::{String, ToString};
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use core::arch::{asm, global_asm};
+use core::arch::global_asm;
 use core::panic::PanicInfo;
 use kukios::command_dispatcher::dispatch_command;
-use kukios::interrupts::input;
+use kukios::interrupts::input_async;
+use kukios::task::{
+    executor::Executor,
+    job::{self, Job},
+    Task,
+};
+use kukios::{println, serial_print, serial_println};
 
 mod asm;
 mod functions;
-mod serial;
-mod vga_buffer;
 
 entry_point!(kernel_main);
 // global_asm!(
@@ -75,6 +79,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("SERIOUS EXCEPTION: HEAP init failed");
+    memory::install(mapper, frame_allocator);
+    kukios::apic::init();
+    kukios::clock::init();
+    kukios::disk::init();
+    kukios::pci::init();
+    kukios::net::e1000::register_driver();
+    kukios::net::virtio::register_driver();
+    kukios::pci::probe_drivers();
+    kukios::net::init();
     // unsafe {
     //     println!("Foo value is: {}", my_adder(1, 1));
     // }
@@ -121,10 +134,22 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // "Reference count is at value of {} now.",
     // Rc::strong_count(&cloned_reference)
     // );
+    #[cfg(test)]
+    test_main();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(shell()));
+    executor.run();
+}
+
+async fn shell() {
     println!("Now in command mode. For help, type help.");
     loop {
-        let x = input();
-        dispatch_command(&x);
+        let x = input_async().await;
+        // As a job, a command that waits for the network or for input lets
+        // the other tasks run meanwhile.
+        let line = x.clone();
+        job::run(Job::new(move || dispatch_command(&line))).await;
         if x == "jailbreak" {
             println!("Out of the command mode. Good luck soldier, you're on your own.");
             // unsafe {
@@ -172,14 +197,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     //         println!("L4 Entry {}: {:?}", i, entry);
     //     }
     // }
-    #[cfg(test)]
-    test_main();
     // #[cfg(test)]
     // test_main();
 
     println!("Works!");
-
-    kukios::hlt_loop();
 }
 
 // extern "C" {
@@ -227,15 +248,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    use kukios::{interrupts::acpi_shutdown, sleep};
+    use kukios::{acpi, exceptions::report_line as report, sleep};
 
-    println!("[fail]");
-    println!(
+    // Straight to the screen and the serial port, skipping either if the
+    // panicking code holds it; `println!` could wait forever or end up in a
+    // remote session's output.
+    report(format_args!("[fail]"));
+    report(format_args!(
         "ERROR: KukiOS panicked: {}. Preparing the system to shutdown.",
         info
-    );
-    sleep(1000000000);
-    unsafe { acpi_shutdown() }
+    ));
+    kukios::backtrace::print();
+    sleep(3000);
+    if let Err(err) = acpi::shutdown() {
+        report(format_args!("Could not shut down: {}", err));
+    }
     kukios::hlt_loop();
 }
 
@@ -300,26 +327,8 @@ async fn _example_task() {
     println!("The async number is {}", number);
 }
 
-pub fn shutdown() {
-    unsafe {
-        asm!(
-            "mov ax, 0x1000",
-            "mov ax, ss",
-            "mov sp, 0xf000",
-            "mov ax, 0x5307",
-            "mov bx, 0x0001",
-            "mov cx, 0x0003",
-            "int 0x15",
-        );
-    }
-}
-
 fn _delay(seconds: u64) {
-    const CYCLES_PER_SECOND: u64 = 2_900_000_000;
-    let target = seconds * CYCLES_PER_SECOND;
-    for _ in 0..target {
-        core::hint::spin_loop();
-    }
+    kukios::sleep(seconds * 1000);
 }
 
 // pub struct MyDevice;
@@ -1,5 +1,12 @@
+use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+lazy_static! {
+    pub static ref FILESYSTEM: Mutex<FileSystem> = Mutex::new(FileSystem::new(1024, 128, 512));
+}
 
 #[repr(C)]
 pub struct SuperBlock {
@@ -51,6 +58,7 @@ pub struct FileSystem {
     superblock: SuperBlock,
     inodes: Vec<Inode>,
     data_blocks: Vec<Vec<u8>>,
+    used_blocks: Vec<bool>,
     dir_entries: Vec<DirEntry>,
 }
 
@@ -60,6 +68,7 @@ impl FileSystem {
             superblock: SuperBlock::new(total_blocks, inode_count, block_size),
             inodes: Vec::new(),
             data_blocks: vec![vec![0; block_size as usize]; total_blocks as usize],
+            used_blocks: vec![false; total_blocks as usize],
             dir_entries: Vec::new(),
         }
     }
@@ -106,6 +115,7 @@ impl FileSystem {
         let mut offset = 0;
         let mut blocks = Vec::new();
 
+        self.release_blocks(inode_index);
         while offset < data.len() {
             let blocks_index = self.allocate_block();
             blocks.push(blocks_index);
@@ -114,7 +124,7 @@ impl FileSystem {
         {
             let inode = &mut self.inodes[inode_index];
             for (i, &block_index) in blocks.iter().enumerate() {
-                if i > inode.blocks.len() {
+                if i >= inode.blocks.len() {
                     break;
                 }
                 inode.blocks[i] = block_index as u32;
@@ -124,6 +134,7 @@ impl FileSystem {
         offset = 0;
         for (_i, &block_index) in blocks.iter().enumerate() {
             let end = usize::min(offset + block_size, data.len());
+            self.data_blocks[block_index].fill(0);
             self.data_blocks[block_index][..end - offset].copy_from_slice(&data[offset..end]);
             offset += block_size;
         }
@@ -144,7 +155,7 @@ impl FileSystem {
         let data_size = inode.data_size as usize;
         let mut offset = 0;
         for &block_index in &inode.blocks {
-            if offset >= buffer.len() {
+            if offset >= buffer.len() || offset >= data_size {
                 break;
             }
             // let end = usize::min(offset + block_size, buffer.len());
@@ -167,9 +178,39 @@ impl FileSystem {
             None
         }
     }
+    pub fn find_file(&self, name: &str) -> Option<usize> {
+        self.dir_entries
+            .iter()
+            .find(|entry| entry.name_as_str() == name)
+            .map(|entry| entry.inode as usize)
+    }
+    pub fn file_size(&self, name: &str) -> Option<usize> {
+        self.find_file(name)
+            .map(|inode_index| self.inodes[inode_index].data_size as usize)
+    }
+    pub fn max_file_size(&self) -> usize {
+        self.superblock.block_size as usize * 12
+    }
+    pub fn list_files(&self) -> Vec<String> {
+        self.dir_entries
+            .iter()
+            .map(|entry| entry.name_as_str().to_string())
+            .collect()
+    }
+    fn release_blocks(&mut self, inode_index: usize) {
+        let inode = &mut self.inodes[inode_index];
+        let used_blocks = (inode.data_size as usize + self.superblock.block_size as usize - 1)
+            / self.superblock.block_size as usize;
+        for block in inode.blocks.iter_mut().take(used_blocks) {
+            self.used_blocks[*block as usize] = false;
+            *block = 0;
+        }
+        inode.data_size = 0;
+    }
     pub fn allocate_block(&mut self) -> usize {
-        for (i, block) in self.data_blocks.iter().enumerate() {
-            if block.iter().all(|&b| b == 0) {
+        for (i, used) in self.used_blocks.iter_mut().enumerate() {
+            if !*used {
+                *used = true;
                 return i;
             }
         }
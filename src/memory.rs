@@ -1,11 +1,27 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Where device registers get mapped, well away from the heap.
+const MMIO_START: u64 = 0x_5555_0000_0000;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+lazy_static! {
+    static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+    static ref FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+}
+
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -19,10 +35,100 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Hands the page table and frame allocator over to the kernel once the heap is
+/// up, so drivers can map registers and allocate DMA memory later on.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Virtual address of `phys` in the bootloader's map of all physical memory.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref()?.translate_addr(virt)
+}
+
+/// Whether `virt` is mapped, or `None` if that can't be told: before
+/// [`install`], or while the mapper is locked by code that just faulted.
+pub fn is_mapped(virt: VirtAddr) -> Option<bool> {
+    let mapper = MAPPER.try_lock()?;
+    Some(mapper.as_ref()?.translate_addr(virt).is_some())
+}
+
+/// Physically contiguous memory a device can read and write on its own.
+pub struct DmaRegion {
+    pub phys: PhysAddr,
+    pub virt: VirtAddr,
+    pub size: usize,
+}
+
+impl DmaRegion {
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+/// Allocates zeroed, physically contiguous frames for `size` bytes of DMA memory.
+pub fn alloc_dma(size: usize) -> Option<DmaRegion> {
+    let frames = (size + 4095) / 4096;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut()?;
+    let mut first = allocator.allocate_frame()?;
+    let mut count = 1;
+    while count < frames {
+        let frame = allocator.allocate_frame()?;
+        if frame.start_address() == first.start_address() + count as u64 * 4096 {
+            count += 1;
+        } else {
+            // Usable regions have holes between them; start over past the gap.
+            first = frame;
+            count = 1;
+        }
+    }
+    let virt = phys_to_virt(first.start_address());
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * 4096) };
+    Some(DmaRegion {
+        phys: first.start_address(),
+        virt,
+        size: frames * 4096,
+    })
+}
+
+/// Maps `size` bytes of device registers at `phys` uncached and returns where they ended up.
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<VirtAddr, &'static str> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("Memory manager not installed.")?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("Memory manager not installed.")?;
+
+    let page_offset = phys.as_u64() % 4096;
+    let pages = (page_offset + size as u64 + 4095) / 4096;
+    let virt_start = NEXT_MMIO.fetch_add(pages * 4096, Ordering::Relaxed);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt_start + i * 4096));
+        let frame = PhysFrame::containing_address(phys + i * 4096);
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, allocator)
+                .map_err(|_| "Mapping device memory failed.")?
+                .flush();
+        }
+    }
+    Ok(VirtAddr::new(virt_start + page_offset))
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -45,6 +151,17 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+static FRAMES_USABLE: AtomicUsize = AtomicUsize::new(0);
+static FRAMES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns `(allocated, usable)` physical frame counts.
+pub fn frame_stats() -> (usize, usize) {
+    (
+        FRAMES_ALLOCATED.load(Ordering::Relaxed),
+        FRAMES_USABLE.load(Ordering::Relaxed),
+    )
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -52,10 +169,12 @@ pub struct BootInfoFrameAllocator {
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+        };
+        FRAMES_USABLE.store(allocator.usable_frames().count(), Ordering::Relaxed);
+        allocator
     }
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memory_map.iter();
@@ -70,6 +189,7 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        FRAMES_ALLOCATED.store(self.next, Ordering::Relaxed);
         frame
     }
 }
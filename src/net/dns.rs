@@ -0,0 +1,329 @@
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
+use spin::Mutex;
+
+use crate::{
+    interrupts::uptime_millis,
+    net::{stack::with_stack, UdpSocket},
+    println,
+    task::{job::block_on, timeout},
+};
+
+const DNS_PORT: u16 = 53;
+const ATTEMPT_TIMEOUT_MS: u64 = 2000;
+const ATTEMPTS: usize = 3;
+/// Classic DNS over UDP never exceeds this without EDNS.
+const MAX_MESSAGE_SIZE: usize = 512;
+/// Cap how long answers stay cached, whatever the TTL says.
+const MAX_CACHE_TTL_SECS: u32 = 3600;
+/// Names cached at most; the one expiring soonest makes room for a new one.
+const MAX_CACHE_ENTRIES: usize = 128;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_NAME_ERROR: u16 = 3;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RecordType {
+    A = 1,
+    Aaaa = 28,
+}
+
+lazy_static! {
+    /// Answers by name and type, with the uptime they expire at.
+    static ref CACHE: Mutex<BTreeMap<(String, RecordType), (Vec<IpAddress>, u64)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Looks up A and AAAA records for `name`, IPv4 answers first. Address
+/// literals resolve to themselves.
+pub fn resolve(name: &str) -> Result<Vec<IpAddress>, &'static str> {
+    if let Ok(address) = name.parse::<IpAddress>() {
+        return Ok(vec![address]);
+    }
+    let mut addresses = lookup(name, RecordType::A, None)?;
+    // Plenty of hosts have no AAAA record; that only matters if A came back empty too.
+    match lookup(name, RecordType::Aaaa, None) {
+        Ok(mut v6) => addresses.append(&mut v6),
+        Err(err) if addresses.is_empty() => return Err(err),
+        Err(_) => {}
+    }
+    if addresses.is_empty() {
+        return Err("Host has no addresses.");
+    }
+    Ok(addresses)
+}
+
+/// Resolves `name` to the first IPv4 address, which is all the stack routes today.
+pub fn resolve_ipv4(name: &str) -> Result<Ipv4Address, &'static str> {
+    block_on(resolve_ipv4_async(name))?
+}
+
+/// Like [`resolve_ipv4`], but for tasks.
+pub async fn resolve_ipv4_async(name: &str) -> Result<Ipv4Address, &'static str> {
+    if let Ok(address) = name.parse::<Ipv4Address>() {
+        return Ok(address);
+    }
+    lookup_async(name, RecordType::A, None)
+        .await?
+        .into_iter()
+        .find_map(|address| match address {
+            IpAddress::Ipv4(address) => Some(address),
+            _ => None,
+        })
+        .ok_or("Host has no IPv4 address.")
+}
+
+/// Queries `server`, or the configured servers in turn, for one record type.
+pub fn lookup(
+    name: &str,
+    record_type: RecordType,
+    server: Option<Ipv4Address>,
+) -> Result<Vec<IpAddress>, &'static str> {
+    block_on(lookup_async(name, record_type, server))?
+}
+
+/// Like [`lookup`], but for tasks.
+pub async fn lookup_async(
+    name: &str,
+    record_type: RecordType,
+    server: Option<Ipv4Address>,
+) -> Result<Vec<IpAddress>, &'static str> {
+    let key = (name.to_ascii_lowercase(), record_type);
+    let now = uptime_millis();
+    if server.is_none() {
+        let mut cache = CACHE.lock();
+        match cache.get(&key) {
+            Some((addresses, expires)) if *expires > now => return Ok(addresses.clone()),
+            Some(_) => {
+                cache.remove(&key);
+            }
+            None => {}
+        }
+    }
+
+    let servers = match server {
+        Some(server) => vec![server],
+        None => with_stack(|stack| stack.dns_servers.clone())?,
+    };
+    if servers.is_empty() {
+        return Err("No DNS server configured.");
+    }
+
+    let mut query = [0u8; MAX_MESSAGE_SIZE];
+    let id = (unsafe { core::arch::x86_64::_rdtsc() } & 0xFFFF) as u16;
+    let query_len = encode_query(&mut query, id, name, record_type)?;
+
+    let socket = UdpSocket::bind(0)?;
+    let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+    let mut result = Err("DNS server did not answer.");
+    'attempts: for attempt in 0..ATTEMPTS {
+        let server = servers[attempt % servers.len()];
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(server), DNS_PORT);
+        socket.send_to(&query[..query_len], endpoint).await?;
+        let deadline = uptime_millis() + ATTEMPT_TIMEOUT_MS;
+        loop {
+            let remaining = Duration::from_millis(deadline.saturating_sub(uptime_millis()));
+            let (count, from) = match timeout(remaining, socket.recv_from(&mut buffer)).await {
+                Ok(received) => received?,
+                Err(_) => break,
+            };
+            if from != endpoint {
+                continue;
+            }
+            if let Some(answer) = parse_response(&buffer[..count], id, record_type) {
+                result = answer;
+                break 'attempts;
+            }
+        }
+    }
+
+    let (addresses, ttl) = result?;
+    let ttl = u64::from(ttl.min(MAX_CACHE_TTL_SECS));
+    let now = uptime_millis();
+    let mut cache = CACHE.lock();
+    cache.retain(|_, (_, expires)| *expires > now);
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        let soonest = cache
+            .iter()
+            .min_by_key(|(_, (_, expires))| *expires)
+            .map(|(key, _)| key.clone());
+        if let Some(soonest) = soonest {
+            cache.remove(&soonest);
+        }
+    }
+    cache.insert(key, (addresses.clone(), now + ttl * 1000));
+    Ok(addresses)
+}
+
+/// Writes a recursive query for `name` into `buffer` and returns its length.
+fn encode_query(
+    buffer: &mut [u8],
+    id: u16,
+    name: &str,
+    record_type: RecordType,
+) -> Result<usize, &'static str> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err("Invalid host name.");
+    }
+    let header = [
+        id,
+        FLAG_RECURSION_DESIRED,
+        1, // one question
+        0,
+        0,
+        0,
+    ];
+    let mut pos = 0;
+    for field in header.iter() {
+        buffer[pos..pos + 2].copy_from_slice(&field.to_be_bytes());
+        pos += 2;
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err("Invalid host name.");
+        }
+        buffer[pos] = label.len() as u8;
+        buffer[pos + 1..pos + 1 + label.len()].copy_from_slice(label.as_bytes());
+        pos += 1 + label.len();
+    }
+    buffer[pos] = 0;
+    pos += 1;
+    buffer[pos..pos + 2].copy_from_slice(&(record_type as u16).to_be_bytes());
+    buffer[pos + 2..pos + 4].copy_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(pos + 4)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]))
+}
+
+/// Returns the offset just past the (possibly compressed) name at `pos`.
+fn skip_name(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + usize::from(len);
+    }
+}
+
+/// Parses a reply to query `id`. `None` means the packet isn't ours and we keep
+/// waiting; otherwise the addresses of `record_type` with the lowest TTL.
+fn parse_response(
+    data: &[u8],
+    id: u16,
+    record_type: RecordType,
+) -> Option<Result<(Vec<IpAddress>, u32), &'static str>> {
+    if read_u16(data, 0)? != id {
+        return None;
+    }
+    let flags = read_u16(data, 2)?;
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match flags & 0xF {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err("Name not found.")),
+        _ => return Some(Err("DNS server failed the query.")),
+    }
+    let questions = read_u16(data, 4)?;
+    let answers = read_u16(data, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(data, pos)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(data, pos)?;
+        let answer_type = read_u16(data, pos)?;
+        let class = read_u16(data, pos + 2)?;
+        let answer_ttl =
+            u32::from(read_u16(data, pos + 4)?) << 16 | u32::from(read_u16(data, pos + 6)?);
+        let length = usize::from(read_u16(data, pos + 8)?);
+        let rdata = data.get(pos + 10..pos + 10 + length)?;
+        pos += 10 + length;
+        if class != CLASS_IN || answer_type != record_type as u16 {
+            // CNAMEs and friends; the records they point at follow in the same answer.
+            continue;
+        }
+        let address = match record_type {
+            RecordType::A if length == 4 => IpAddress::Ipv4(Ipv4Address::from_bytes(rdata)),
+            RecordType::Aaaa if length == 16 => IpAddress::Ipv6(Ipv6Address::from_bytes(rdata)),
+            _ => continue,
+        };
+        addresses.push(address);
+        ttl = ttl.min(answer_ttl);
+    }
+    Some(Ok((addresses, if ttl == u32::MAX { 0 } else { ttl })))
+}
+
+pub fn nslookup(args: &[&str]) {
+    let (name, server) = match args {
+        [name] => (*name, None),
+        [name, server] => match server.parse::<Ipv4Address>() {
+            Ok(server) => (*name, Some(server)),
+            Err(_) => {
+                println!("nslookup: {}: not an IPv4 address", server);
+                return;
+            }
+        },
+        _ => {
+            println!("Usage: nslookup <name> [server]");
+            return;
+        }
+    };
+    match server {
+        Some(server) => println!("Server:  {}", server),
+        None => match with_stack(|stack| stack.dns_servers.first().copied()) {
+            Ok(Some(server)) => println!("Server:  {}", server),
+            Ok(None) => {
+                println!("nslookup: No DNS server configured.");
+                return;
+            }
+            Err(err) => {
+                println!("nslookup: {}", err);
+                return;
+            }
+        },
+    }
+    let v4 = lookup(name, RecordType::A, server);
+    let v6 = lookup(name, RecordType::Aaaa, server);
+    let addresses: Vec<IpAddress> = v4.iter().chain(v6.iter()).flatten().copied().collect();
+    if addresses.is_empty() {
+        let err = v4.and(v6).err().unwrap_or("Host has no addresses.");
+        println!("nslookup: {}: {}", name, err);
+        return;
+    }
+    println!("Name:    {}", name);
+    for address in addresses {
+        println!("Address: {}", address);
+    }
+}
+
+#[test_case]
+fn test_encode_query() {
+    let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+    let len = encode_query(&mut buffer, 0x1234, "kukiweb.cz", RecordType::A).unwrap();
+    assert_eq!(&buffer[..4], &[0x12, 0x34, 0x01, 0x00]);
+    assert_eq!(&buffer[12..24], b"\x07kukiweb\x02cz\x00");
+    assert_eq!(&buffer[24..len], &[0, 1, 0, 1]);
+}
+
+#[test_case]
+fn test_skip_compressed_name() {
+    let data = [3, b'w', b'w', b'w', 0xC0, 12, 0xFF];
+    assert_eq!(skip_name(&data, 0), Some(6));
+    assert_eq!(skip_name(&[0], 0), Some(1));
+}
@@ -0,0 +1,399 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use x86_64::PhysAddr;
+
+use crate::{
+    interrupts::{register_irq_handler, uptime_millis},
+    memory::{alloc_dma, map_mmio, DmaRegion},
+    net::{self, register_nic, Nic, MAX_FRAME_SIZE},
+    pci::{self, Bar, PciDevice, PciDriver},
+    println,
+};
+
+const E1000_REG_CTRL: u32 = 0x0000;
+const E1000_REG_STATUS: u32 = 0x0008;
+const E1000_REG_EERD: u32 = 0x0014;
+const E1000_REG_ICR: u32 = 0x00C0;
+const E1000_REG_IMS: u32 = 0x00D0;
+const E1000_REG_IMC: u32 = 0x00D8;
+const E1000_REG_RCTL: u32 = 0x0100;
+const E1000_REG_TCTL: u32 = 0x0400;
+const E1000_REG_TIPG: u32 = 0x0410;
+const E1000_REG_RDBAL: u32 = 0x2800;
+const E1000_REG_RDBAH: u32 = 0x2804;
+const E1000_REG_RDLEN: u32 = 0x2808;
+const E1000_REG_RDH: u32 = 0x2810;
+const E1000_REG_RDT: u32 = 0x2818;
+const E1000_REG_TDBAL: u32 = 0x3800;
+const E1000_REG_TDBAH: u32 = 0x3804;
+const E1000_REG_TDLEN: u32 = 0x3808;
+const E1000_REG_TDH: u32 = 0x3810;
+const E1000_REG_TDT: u32 = 0x3818;
+const E1000_REG_MTA: u32 = 0x5200;
+const E1000_REG_RAL: u32 = 0x5400;
+const E1000_REG_RAH: u32 = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_PHY_RST: u32 = 1 << 31;
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const DESC_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+const NUM_RX_DESC: usize = 32;
+const NUM_TX_DESC: usize = 8;
+const BUFFER_SIZE: usize = 2048;
+const MMIO_SIZE: usize = 128 * 1024;
+/// How long a reset or an EEPROM read may take before the card is given up on.
+const E1000_TIMEOUT_MS: u64 = 100;
+/// Upper bound on register polls, so a wait ends even with interrupts off.
+const E1000_MAX_POLLS: u32 = 1_000_000;
+
+/// The 82540EM is what QEMU emulates for `-nic` by default.
+const INTEL_VENDOR_ID: u16 = 0x8086;
+const E1000_DEVICE_IDS: [u16; 2] = [0x100E, 0x100F];
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RxDesc {
+    buffer_addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TxDesc {
+    buffer_addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+/// Register base for the interrupt handler, which can't lock the driver.
+static MMIO_BASE: AtomicU64 = AtomicU64::new(0);
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+
+pub fn register_driver() {
+    for &device_id in &E1000_DEVICE_IDS {
+        pci::register_driver(PciDriver {
+            name: "e1000",
+            vendor_id: INTEL_VENDOR_ID,
+            device_id: Some(device_id),
+            probe,
+        });
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    let bar = match device.bars[0] {
+        Some(Bar::Memory { address, .. }) => address,
+        _ => return Err("BAR0 is not a memory BAR."),
+    };
+    device.enable();
+    let mmio = map_mmio(PhysAddr::new(bar), MMIO_SIZE)?;
+    let mut e1000 = E1000::new(mmio.as_u64())?;
+    e1000.init()?;
+    register_irq_handler(device.interrupt_line, handle_interrupt)?;
+    let mac = e1000.mac;
+    println!(
+        "[OK] e1000 at {}: MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, IRQ {}",
+        device.address, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], device.interrupt_line
+    );
+    register_nic(Box::new(e1000));
+    Ok(())
+}
+
+fn read_register(base: u64, register: u32) -> u32 {
+    unsafe { read_volatile((base + u64::from(register)) as *const u32) }
+}
+
+fn write_register(base: u64, register: u32, value: u32) {
+    unsafe { write_volatile((base + u64::from(register)) as *mut u32, value) }
+}
+
+/// Runs in interrupt context: acknowledges the cause and wakes the network task.
+fn handle_interrupt() {
+    let base = MMIO_BASE.load(Ordering::Relaxed);
+    if base == 0 {
+        return;
+    }
+    // Reading ICR clears it, which also deasserts the level-triggered INTx line.
+    let cause = read_register(base, E1000_REG_ICR);
+    if cause & ICR_LSC != 0 {
+        LINK_UP.store(
+            read_register(base, E1000_REG_STATUS) & STATUS_LU != 0,
+            Ordering::Relaxed,
+        );
+    }
+    if cause & (ICR_LSC | ICR_RXT0 | ICR_RXDMT0 | ICR_RXO | ICR_TXDW) != 0 {
+        net::wake();
+    }
+}
+
+pub struct E1000 {
+    base: u64,
+    mac: [u8; 6],
+    rx_ring: DmaRegion,
+    rx_buffers: DmaRegion,
+    rx_next: usize,
+    tx_ring: DmaRegion,
+    tx_buffers: DmaRegion,
+    tx_next: usize,
+}
+
+// The rings are only touched while the driver is locked.
+unsafe impl Send for E1000 {}
+
+impl E1000 {
+    pub fn new(base: u64) -> Result<Self, &'static str> {
+        let out_of_memory = "Not enough memory for e1000 rings.";
+        Ok(Self {
+            base,
+            mac: [0; 6],
+            rx_ring: alloc_dma(NUM_RX_DESC * core::mem::size_of::<RxDesc>()).ok_or(out_of_memory)?,
+            rx_buffers: alloc_dma(NUM_RX_DESC * BUFFER_SIZE).ok_or(out_of_memory)?,
+            rx_next: 0,
+            tx_ring: alloc_dma(NUM_TX_DESC * core::mem::size_of::<TxDesc>()).ok_or(out_of_memory)?,
+            tx_buffers: alloc_dma(NUM_TX_DESC * BUFFER_SIZE).ok_or(out_of_memory)?,
+            tx_next: 0,
+        })
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        read_register(self.base, register)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        write_register(self.base, register, value)
+    }
+
+    /// Polls `register` until `ready` accepts its value, failing with
+    /// `error` after [`E1000_TIMEOUT_MS`].
+    fn wait_for(
+        &self,
+        register: u32,
+        ready: impl Fn(u32) -> bool,
+        error: &'static str,
+    ) -> Result<u32, &'static str> {
+        let deadline = uptime_millis() + E1000_TIMEOUT_MS;
+        for _ in 0..E1000_MAX_POLLS {
+            let value = self.read(register);
+            if ready(value) {
+                return Ok(value);
+            }
+            if uptime_millis() > deadline {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Err(error)
+    }
+
+    fn read_eeprom(&self, word: u8) -> Result<u16, &'static str> {
+        self.write(E1000_REG_EERD, EERD_START | u32::from(word) << 8);
+        let value = self.wait_for(
+            E1000_REG_EERD,
+            |value| value & EERD_DONE != 0,
+            "EEPROM read timed out.",
+        )?;
+        Ok((value >> 16) as u16)
+    }
+
+    pub fn init(&mut self) -> Result<(), &'static str> {
+        self.write(E1000_REG_IMC, 0xFFFF_FFFF);
+        self.write(E1000_REG_CTRL, self.read(E1000_REG_CTRL) | CTRL_RST);
+        self.wait_for(
+            E1000_REG_CTRL,
+            |ctrl| ctrl & CTRL_RST == 0,
+            "Reset timed out.",
+        )?;
+        self.write(E1000_REG_IMC, 0xFFFF_FFFF);
+        self.read(E1000_REG_ICR);
+
+        for i in 0..3 {
+            let word = self.read_eeprom(i)?;
+            self.mac[i as usize * 2] = word as u8;
+            self.mac[i as usize * 2 + 1] = (word >> 8) as u8;
+        }
+        let mac = self.mac;
+        self.write(
+            E1000_REG_RAL,
+            u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+        );
+        self.write(
+            E1000_REG_RAH,
+            u32::from(mac[4]) | u32::from(mac[5]) << 8 | 1 << 31,
+        );
+        for i in 0..128 {
+            self.write(E1000_REG_MTA + i * 4, 0);
+        }
+
+        let ctrl = self.read(E1000_REG_CTRL);
+        self.write(E1000_REG_CTRL, (ctrl | CTRL_SLU | CTRL_ASDE) & !CTRL_PHY_RST);
+
+        self.init_rx();
+        self.init_tx();
+
+        LINK_UP.store(self.read(E1000_REG_STATUS) & STATUS_LU != 0, Ordering::Relaxed);
+        MMIO_BASE.store(self.base, Ordering::Relaxed);
+        self.write(
+            E1000_REG_IMS,
+            ICR_LSC | ICR_RXT0 | ICR_RXDMT0 | ICR_RXO | ICR_TXDW,
+        );
+        Ok(())
+    }
+
+    fn rx_desc(&self, index: usize) -> *mut RxDesc {
+        unsafe { self.rx_ring.as_mut_ptr::<RxDesc>().add(index) }
+    }
+
+    fn tx_desc(&self, index: usize) -> *mut TxDesc {
+        unsafe { self.tx_ring.as_mut_ptr::<TxDesc>().add(index) }
+    }
+
+    fn init_rx(&mut self) {
+        for i in 0..NUM_RX_DESC {
+            let desc = RxDesc {
+                buffer_addr: self.rx_buffers.phys.as_u64() + (i * BUFFER_SIZE) as u64,
+                length: 0,
+                checksum: 0,
+                status: 0,
+                errors: 0,
+                special: 0,
+            };
+            unsafe { write_volatile(self.rx_desc(i), desc) };
+        }
+        let ring = self.rx_ring.phys.as_u64();
+        self.write(E1000_REG_RDBAL, ring as u32);
+        self.write(E1000_REG_RDBAH, (ring >> 32) as u32);
+        self.write(
+            E1000_REG_RDLEN,
+            (NUM_RX_DESC * core::mem::size_of::<RxDesc>()) as u32,
+        );
+        self.write(E1000_REG_RDH, 0);
+        self.write(E1000_REG_RDT, (NUM_RX_DESC - 1) as u32);
+        self.rx_next = 0;
+        // Buffer size 2048 is BSIZE=00; strip the CRC so frames end at the payload.
+        self.write(E1000_REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+    }
+
+    fn init_tx(&mut self) {
+        for i in 0..NUM_TX_DESC {
+            let desc = TxDesc {
+                buffer_addr: self.tx_buffers.phys.as_u64() + (i * BUFFER_SIZE) as u64,
+                length: 0,
+                cso: 0,
+                cmd: 0,
+                status: DESC_STATUS_DD,
+                css: 0,
+                special: 0,
+            };
+            unsafe { write_volatile(self.tx_desc(i), desc) };
+        }
+        let ring = self.tx_ring.phys.as_u64();
+        self.write(E1000_REG_TDBAL, ring as u32);
+        self.write(E1000_REG_TDBAH, (ring >> 32) as u32);
+        self.write(
+            E1000_REG_TDLEN,
+            (NUM_TX_DESC * core::mem::size_of::<TxDesc>()) as u32,
+        );
+        self.write(E1000_REG_TDH, 0);
+        self.write(E1000_REG_TDT, 0);
+        self.tx_next = 0;
+        // Collision threshold 0x10 and distance 0x40, the full-duplex values from the manual.
+        self.write(
+            E1000_REG_TCTL,
+            TCTL_EN | TCTL_PSP | 0x10 << 4 | 0x40 << 12,
+        );
+        self.write(E1000_REG_TIPG, 10 | 8 << 10 | 6 << 20);
+    }
+}
+
+impl Nic for E1000 {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+    fn link_up(&self) -> bool {
+        LINK_UP.load(Ordering::Relaxed)
+    }
+    fn send(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("Frame too large.");
+        }
+        let desc = self.tx_desc(self.tx_next);
+        let mut current = unsafe { read_volatile(desc) };
+        if current.status & DESC_STATUS_DD == 0 {
+            return Err("Transmit ring full.");
+        }
+        let buffer = self.tx_buffers.virt.as_u64() + (self.tx_next * BUFFER_SIZE) as u64;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer as *mut u8, frame.len());
+        }
+        current.length = frame.len() as u16;
+        current.cmd = TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS;
+        current.status = 0;
+        unsafe { write_volatile(desc, current) };
+        self.tx_next = (self.tx_next + 1) % NUM_TX_DESC;
+        self.write(E1000_REG_TDT, self.tx_next as u32);
+        Ok(())
+    }
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let desc = self.rx_desc(self.rx_next);
+            let mut current = unsafe { read_volatile(desc) };
+            if current.status & DESC_STATUS_DD == 0 {
+                return None;
+            }
+            let buffer = self.rx_buffers.virt.as_u64() + (self.rx_next * BUFFER_SIZE) as u64;
+            let length = usize::from(current.length);
+            let good = current.errors == 0 && current.status & RX_STATUS_EOP != 0;
+            let frame = if good {
+                Some(unsafe { core::slice::from_raw_parts(buffer as *const u8, length) }.to_vec())
+            } else {
+                None
+            };
+            current.status = 0;
+            unsafe { write_volatile(desc, current) };
+            // Hand the descriptor back to the card.
+            self.write(E1000_REG_RDT, self.rx_next as u32);
+            self.rx_next = (self.rx_next + 1) % NUM_RX_DESC;
+            if frame.is_some() {
+                return frame;
+            }
+        }
+    }
+}
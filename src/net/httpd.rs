@@ -0,0 +1,254 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{
+    net::{service::Service, TcpStream},
+    println, vfs,
+};
+
+const DEFAULT_PORT: u16 = 80;
+const MAX_HEADER_SIZE: usize = 8192;
+
+static SERVICE: Service = Service::new("httpd");
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            reason,
+            content_type,
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str) -> Self {
+        let body = format!("{} {}\n", status, reason).into_bytes();
+        Response::new(status, reason, "text/plain; charset=utf-8", body)
+    }
+
+    fn head(&self) -> String {
+        format!(
+            "HTTP/1.0 {} {}\r\nServer: KukiOS\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len()
+        )
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Undoes `%XX` escapes in a request path; `None` if they are malformed.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = core::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn content_type(path: &str, body: &[u8]) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        _ if core::str::from_utf8(body).is_ok() => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn listing(path: &str, entries: &[String]) -> Response {
+    let title = escape_html(if path.is_empty() { "/" } else { path });
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    for entry in entries {
+        html.push_str(&format!(
+            "<li><a href=\"{}/{}\">{}</a></li>\n",
+            escape_html(path),
+            escape_html(entry),
+            escape_html(entry)
+        ));
+    }
+    html.push_str("</ul></body></html>\n");
+    Response::new(200, "OK", "text/html; charset=utf-8", html.into_bytes())
+}
+
+fn get(path: &str) -> Response {
+    // Directory links end in a slash, which the filesystem doesn't expect.
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        let files: Vec<String> = vfs::list(path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| vfs::is_file(entry))
+            .collect();
+        return listing(path, &files);
+    }
+    // Only the filesystem is served. Devices could block us, like the
+    // console, or be the whole disk.
+    if !vfs::is_file(path) {
+        return Response::error(403, "Forbidden");
+    }
+    match vfs::read(path, None) {
+        Ok(body) => Response::new(200, "OK", content_type(path, &body), body),
+        Err(_) => Response::error(404, "Not Found"),
+    }
+}
+
+/// Stores an upload; `path` is a file and `body` fits, `handle` checked both.
+fn put(path: &str, body: &[u8]) -> Response {
+    match vfs::write(path, body) {
+        Ok(_) => Response::error(201, "Created"),
+        // Out of inodes or blocks.
+        Err(_) => Response::error(507, "Insufficient Storage"),
+    }
+}
+
+/// Reads the request head and, for uploads, the body, then answers it.
+async fn handle(stream: &mut TcpStream) -> Result<(), &'static str> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let header_end = loop {
+        if let Some(index) = find(&request, b"\r\n\r\n") {
+            break index;
+        }
+        if request.len() > MAX_HEADER_SIZE {
+            let response = Response::error(400, "Bad Request");
+            stream.write_all(response.head().as_bytes()).await?;
+            return stream.write_all(&response.body).await;
+        }
+        let count = stream.read(&mut buffer).await?;
+        if count == 0 {
+            return Err("Connection closed mid-request.");
+        }
+        request.extend_from_slice(&buffer[..count]);
+    };
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or("").split(' ');
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+    // Queries mean nothing to a file server.
+    let path = target.split('?').next().unwrap_or("");
+
+    let response = match (method, percent_decode(path)) {
+        (_, None) => Response::error(400, "Bad Request"),
+        (_, Some(path)) if !path.starts_with('/') => Response::error(400, "Bad Request"),
+        ("GET" | "HEAD", Some(path)) => get(&path),
+        // Uploads only go to the filesystem, never to devices or /proc.
+        ("PUT", Some(path)) if !vfs::is_file(&path) => Response::error(403, "Forbidden"),
+        ("PUT", Some(path)) => match content_length {
+            None => Response::error(411, "Length Required"),
+            // Refused before the body is read, so it is never held in memory.
+            Some(length) if length > vfs::max_file_size(&path).unwrap_or(0) => {
+                Response::error(413, "Payload Too Large")
+            }
+            Some(length) => {
+                let mut body = request[header_end + 4..].to_vec();
+                while body.len() < length {
+                    let count = stream.read(&mut buffer).await?;
+                    if count == 0 {
+                        return Err("Connection closed mid-upload.");
+                    }
+                    body.extend_from_slice(&buffer[..count]);
+                }
+                body.truncate(length);
+                put(&path, &body)
+            }
+        },
+        _ => Response::error(501, "Not Implemented"),
+    };
+    stream.write_all(response.head().as_bytes()).await?;
+    if method != "HEAD" {
+        stream.write_all(&response.body).await?;
+    }
+    Ok(())
+}
+
+/// Answers one HTTP/1.0 request on `stream` and closes it.
+pub async fn serve(mut stream: TcpStream) {
+    let result = handle(&mut stream).await;
+    if let Err(err) = result.and(stream.flush().await) {
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |peer| peer.to_string());
+        println!("[FAIL] httpd: {}: {}", peer, err);
+    }
+}
+
+pub fn httpd(args: &[&str]) {
+    let port = match args {
+        [] | ["status"] => {
+            match SERVICE.port() {
+                Some(port) => println!("httpd: listening on port {}", port),
+                None => println!("httpd: not running"),
+            }
+            return;
+        }
+        ["stop"] => {
+            match SERVICE.stop() {
+                Ok(()) => println!("[OK] httpd stopped."),
+                Err(err) => println!("httpd: {}", err),
+            }
+            return;
+        }
+        ["start"] => Some(DEFAULT_PORT),
+        ["start", port] => port.parse().ok(),
+        _ => {
+            println!("Usage: httpd [status | start [port] | stop]");
+            return;
+        }
+    };
+    let port = match port {
+        Some(port) => port,
+        None => {
+            println!("httpd: invalid port");
+            return;
+        }
+    };
+    match SERVICE.start(port, serve) {
+        Ok(()) => println!("[OK] httpd listening on port {}.", port),
+        Err(err) => println!("httpd: {}", err),
+    }
+}
@@ -0,0 +1,180 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use smoltcp::{
+    phy::{Device, DeviceCapabilities, RxToken, TxToken},
+    time::Instant,
+};
+use spin::Mutex;
+
+pub mod dns;
+pub mod e1000;
+pub mod httpd;
+pub mod loopback;
+pub mod ntp;
+pub mod pcap;
+pub mod ping;
+pub mod service;
+pub mod socket;
+pub mod stack;
+pub mod telnet;
+pub mod tftp;
+pub mod virtio;
+
+pub use dns::nslookup;
+pub use httpd::httpd;
+pub use ntp::ntpdate;
+pub use pcap::pcap;
+pub use ping::ping;
+pub use socket::{TcpListener, TcpStream, UdpSocket};
+pub use stack::{arp, ifconfig, init, poll, route, with_stack};
+pub use telnet::telnetd;
+pub use tftp::tftp;
+
+/// Largest Ethernet frame we send or accept, header included, FCS excluded.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// What the network stack needs from a network card driver.
+pub trait Nic: Send {
+    fn name(&self) -> &'static str;
+    fn mac_address(&self) -> [u8; 6];
+    fn link_up(&self) -> bool;
+    /// Queues one Ethernet frame for sending.
+    fn send(&mut self, frame: &[u8]) -> Result<(), &'static str>;
+    /// Takes the next received Ethernet frame, if any arrived.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+lazy_static! {
+    static ref NICS: Mutex<Vec<Box<dyn Nic>>> = Mutex::new(Vec::new());
+}
+
+static NET_WAKER: AtomicWaker = AtomicWaker::new();
+static NET_EVENT: AtomicBool = AtomicBool::new(false);
+
+/// Tells the network task there is work: frames arrived, the link changed or a
+/// timer ran out. Safe to call from interrupt handlers.
+pub fn wake() {
+    NET_EVENT.store(true, Ordering::SeqCst);
+    NET_WAKER.wake();
+}
+
+/// Resolves once [`wake`] has been called since it last resolved.
+pub fn next_event() -> NetEvent {
+    NetEvent
+}
+
+pub struct NetEvent;
+
+impl Future for NetEvent {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if NET_EVENT.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        NET_WAKER.register(cx.waker());
+        if NET_EVENT.swap(false, Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub fn register_nic(nic: Box<dyn Nic>) {
+    NICS.lock().push(nic);
+}
+
+/// Takes the first detected card, if any, so the interface can own it.
+pub fn take_device() -> NicDevice {
+    let mut nics = NICS.lock();
+    let nic = if nics.is_empty() {
+        None
+    } else {
+        Some(nics.remove(0))
+    };
+    NicDevice::new(nic)
+}
+
+/// Adapts a [`Nic`] to smoltcp's `phy::Device`. Without a card nothing is
+/// received and everything sent is dropped.
+pub struct NicDevice {
+    nic: Option<Box<dyn Nic>>,
+}
+
+impl NicDevice {
+    pub fn new(nic: Option<Box<dyn Nic>>) -> Self {
+        NicDevice { nic }
+    }
+    pub fn nic(&self) -> Option<&dyn Nic> {
+        self.nic.as_deref()
+    }
+    /// Takes the card away, e.g. to put it into a new interface.
+    pub fn take_nic(&mut self) -> Option<Box<dyn Nic>> {
+        self.nic.take()
+    }
+    /// The card's address, or a locally administered one without a card.
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.nic
+            .as_ref()
+            .map_or([0x02, 0, 0, 0, 0, 0], |nic| nic.mac_address())
+    }
+}
+
+impl<'a> Device<'a> for NicDevice {
+    type RxToken = NicRxToken;
+    type TxToken = NicTxToken<'a>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.nic.as_mut()?.receive()?;
+        pcap::record(&frame);
+        Some((NicRxToken(frame), NicTxToken(self)))
+    }
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(NicTxToken(self))
+    }
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_SIZE;
+        caps.max_burst_size = Some(1);
+        caps
+    }
+}
+
+pub struct NicRxToken(Vec<u8>);
+
+impl RxToken for NicRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        stack::observe_frame(&self.0);
+        f(&mut self.0)
+    }
+}
+
+pub struct NicTxToken<'a>(&'a mut NicDevice);
+
+impl<'a> TxToken for NicTxToken<'a> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut frame = vec![0u8; len];
+        let result = f(&mut frame)?;
+        if let Some(nic) = self.0.nic.as_mut() {
+            nic.send(&frame).map_err(|_| smoltcp::Error::Exhausted)?;
+            // Only what actually went out, like a capture on the wire.
+            pcap::record(&frame);
+        }
+        Ok(result)
+    }
+}
@@ -0,0 +1,255 @@
+use alloc::string::{String, ToString};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use spin::Mutex;
+
+use crate::{
+    clock,
+    interrupts::uptime_millis,
+    net::{dns, stack::with_stack, UdpSocket},
+    println,
+    task::{job::block_on, sleep_until, timeout},
+};
+
+const NTP_PORT: u16 = 123;
+const PACKET_SIZE: usize = 48;
+/// Seconds from the NTP epoch, 1900, to the Unix epoch.
+const UNIX_EPOCH_IN_NTP: u64 = 2_208_988_800;
+const ATTEMPT_TIMEOUT_MS: u64 = 2000;
+const ATTEMPTS: usize = 3;
+/// Gives DHCP, or its static fallback, time to configure the interface first.
+const FIRST_SYNC_DELAY_MS: u64 = 15_000;
+const SYNC_INTERVAL_MS: u64 = 60 * 60 * 1000;
+const RETRY_INTERVAL_MS: u64 = 60 * 1000;
+/// QEMU's user-mode network forwards this address to the host.
+const DEFAULT_SERVER: &str = "10.0.2.2";
+
+/// Version 4, client mode.
+const REQUEST_HEADER: u8 = 4 << 3 | 3;
+const MODE_SERVER: u8 = 4;
+
+lazy_static! {
+    /// Where the periodic sync gets the time from.
+    static ref SERVER: Mutex<String> = Mutex::new(String::from(DEFAULT_SERVER));
+}
+
+/// The server's clock, read when its answer arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sample {
+    unix_millis: u64,
+    received_at: u64,
+    /// Round trip, minus the server's processing time.
+    delay_ms: u64,
+}
+
+/// Converts a 64-bit NTP timestamp. Seconds that look older than the Unix
+/// epoch belong to era 1, which starts in 2036.
+fn ntp_to_unix_millis(timestamp: u64) -> u64 {
+    let mut seconds = timestamp >> 32;
+    if seconds < UNIX_EPOCH_IN_NTP {
+        seconds += 1 << 32;
+    }
+    let fraction = timestamp & 0xFFFF_FFFF;
+    (seconds - UNIX_EPOCH_IN_NTP) * 1000 + (fraction * 1000 >> 32)
+}
+
+fn read_u64(data: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[pos..pos + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// A client request. `marker` goes in the transmit timestamp, which the
+/// server echoes back as the originate timestamp; our own clock may not be set.
+fn encode_request(marker: u64) -> [u8; PACKET_SIZE] {
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0] = REQUEST_HEADER;
+    packet[40..48].copy_from_slice(&marker.to_be_bytes());
+    packet
+}
+
+/// Parses an answer to the request carrying `marker`, sent and received at the
+/// given uptimes. `None` means the packet isn't an answer to it.
+fn parse_response(
+    data: &[u8],
+    marker: u64,
+    sent_at: u64,
+    received_at: u64,
+) -> Option<Result<Sample, &'static str>> {
+    if data.len() < PACKET_SIZE || data[0] & 0x7 != MODE_SERVER || read_u64(data, 24) != marker {
+        return None;
+    }
+    if data[1] == 0 {
+        // A kiss-o'-death packet, telling us to go away.
+        return Some(Err("Server refused the request."));
+    }
+    if data[0] >> 6 == 3 {
+        return Some(Err("Server clock is not synchronized."));
+    }
+    let server_received = ntp_to_unix_millis(read_u64(data, 32));
+    let server_sent = ntp_to_unix_millis(read_u64(data, 40));
+    let delay_ms =
+        (received_at - sent_at).saturating_sub(server_sent.saturating_sub(server_received));
+    Some(Ok(Sample {
+        unix_millis: server_sent + delay_ms / 2,
+        received_at,
+        delay_ms,
+    }))
+}
+
+fn new_marker() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Like [`query_async`], but for the shell.
+fn query(server: Ipv4Address) -> Result<Sample, &'static str> {
+    block_on(query_async(server))?
+}
+
+/// Asks `server` for the time, waiting for the answer.
+async fn query_async(server: Ipv4Address) -> Result<Sample, &'static str> {
+    let endpoint = IpEndpoint::new(IpAddress::Ipv4(server), NTP_PORT);
+    let socket = UdpSocket::bind(0)?;
+    let mut buffer = [0u8; PACKET_SIZE];
+    for _ in 0..ATTEMPTS {
+        let marker = new_marker();
+        let sent_at = uptime_millis();
+        socket.send_to(&encode_request(marker), endpoint).await?;
+        let deadline = sent_at + ATTEMPT_TIMEOUT_MS;
+        loop {
+            let remaining = Duration::from_millis(deadline.saturating_sub(uptime_millis()));
+            let (count, from) = match timeout(remaining, socket.recv_from(&mut buffer)).await {
+                Ok(received) => received?,
+                Err(_) => break,
+            };
+            if from != endpoint {
+                continue;
+            }
+            if let Some(answer) = parse_response(&buffer[..count], marker, sent_at, uptime_millis())
+            {
+                return answer;
+            }
+        }
+    }
+    Err("NTP server did not answer.")
+}
+
+/// Sets the clock from `sample` and returns how far off it was, if it was set.
+fn apply(sample: &Sample) -> Option<i64> {
+    let now = sample.unix_millis + (uptime_millis() - sample.received_at);
+    let before = clock::now_millis();
+    clock::set_millis(now);
+    before.map(|before| now as i64 - before as i64)
+}
+
+/// Syncs the clock shortly after boot and then every hour, retrying every
+/// minute while the server can't be reached.
+async fn sync_task() {
+    let mut next = uptime_millis() + FIRST_SYNC_DELAY_MS;
+    let mut announced = false;
+    loop {
+        sleep_until(next).await;
+        let configured = with_stack(|stack| stack.ipv4_address().is_some()).unwrap_or(false);
+        let server = SERVER.lock().clone();
+        let sample = if configured {
+            match dns::resolve_ipv4_async(&server).await {
+                Ok(address) => query_async(address).await,
+                Err(err) => Err(err),
+            }
+        } else {
+            Err("Interface is not configured.")
+        };
+        next = uptime_millis()
+            + match sample {
+                Ok(sample) => {
+                    apply(&sample);
+                    if !announced {
+                        if let Some(now) = clock::now() {
+                            println!("[OK] Clock set from NTP server {}: {}", server, now);
+                        }
+                        announced = true;
+                    }
+                    SYNC_INTERVAL_MS
+                }
+                Err(_) => RETRY_INTERVAL_MS,
+            };
+    }
+}
+
+/// Starts the periodic sync. Called once the network stack is up.
+pub fn init() {
+    crate::task::spawn(sync_task());
+}
+
+pub fn ntpdate(args: &[&str]) {
+    let (server, remember) = match args {
+        [] => (SERVER.lock().clone(), false),
+        [server] => (server.to_string(), false),
+        ["-s", server] => (server.to_string(), true),
+        _ => {
+            println!("Usage: ntpdate [-s] [server]");
+            println!("  -s  also use the server for the periodic sync");
+            return;
+        }
+    };
+    let sample = dns::resolve_ipv4(&server).and_then(query);
+    let sample = match sample {
+        Ok(sample) => sample,
+        Err(err) => {
+            println!("ntpdate: {}: {}", server, err);
+            return;
+        }
+    };
+    let offset = apply(&sample);
+    if remember {
+        *SERVER.lock() = server.clone();
+    }
+    let now = clock::now().expect("the clock was just set");
+    match offset {
+        Some(offset) => println!(
+            "{}  server {}, offset {} ms, delay {} ms",
+            now, server, offset, sample.delay_ms
+        ),
+        None => println!(
+            "{}  server {}, clock set, delay {} ms",
+            now, server, sample.delay_ms
+        ),
+    }
+}
+
+#[test_case]
+fn test_ntp_timestamp_conversion() {
+    assert_eq!(ntp_to_unix_millis(UNIX_EPOCH_IN_NTP << 32), 0);
+    assert_eq!(
+        ntp_to_unix_millis((UNIX_EPOCH_IN_NTP + 1) << 32 | 0x8000_0000),
+        1500
+    );
+    // Second 0 of era 1 is 2036-02-07 06:28:16 UTC.
+    assert_eq!(
+        ntp_to_unix_millis(0),
+        ((1u64 << 32) - UNIX_EPOCH_IN_NTP) * 1000
+    );
+}
+
+#[test_case]
+fn test_parse_response() {
+    let marker = 0x1122_3344_5566_7788;
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0] = 4 << 3 | MODE_SERVER;
+    packet[1] = 2;
+    packet[24..32].copy_from_slice(&u64::to_be_bytes(marker));
+    packet[32..40].copy_from_slice(&u64::to_be_bytes((UNIX_EPOCH_IN_NTP + 100) << 32));
+    packet[40..48].copy_from_slice(&u64::to_be_bytes((UNIX_EPOCH_IN_NTP + 100) << 32));
+    let sample = parse_response(&packet, marker, 1000, 1040)
+        .unwrap()
+        .unwrap();
+    assert_eq!(sample.delay_ms, 40);
+    assert_eq!(sample.unix_millis, 100_020);
+    assert!(parse_response(&packet, marker + 1, 1000, 1040).is_none());
+    packet[1] = 0;
+    assert!(parse_response(&packet, marker, 1000, 1040)
+        .unwrap()
+        .is_err());
+}
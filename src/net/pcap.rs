@@ -0,0 +1,308 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use lazy_static::lazy_static;
+use smoltcp::wire::IpAddress;
+use spin::Mutex;
+
+use crate::{clock, interrupts::uptime_millis, println, serial_println, vfs};
+
+/// Captures are kept in memory until stopped, so they are capped.
+const MAX_CAPTURE_SIZE: usize = 4 * 1024 * 1024;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Arp,
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+/// Every set condition must match for a frame to be kept.
+#[derive(Default)]
+struct Filter {
+    protocol: Option<Protocol>,
+    port: Option<u16>,
+    host: Option<IpAddress>,
+}
+
+fn address_bytes(address: &IpAddress) -> &[u8] {
+    match address {
+        IpAddress::Ipv4(address) => address.as_bytes(),
+        IpAddress::Ipv6(address) => address.as_bytes(),
+        _ => &[],
+    }
+}
+
+impl Filter {
+    fn parse(args: &[&str]) -> Result<Filter, &'static str> {
+        let mut filter = Filter::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "arp" => filter.protocol = Some(Protocol::Arp),
+                "icmp" => filter.protocol = Some(Protocol::Icmp),
+                "tcp" => filter.protocol = Some(Protocol::Tcp),
+                "udp" => filter.protocol = Some(Protocol::Udp),
+                "port" => {
+                    let port = args.next().and_then(|port| port.parse().ok());
+                    filter.port = Some(port.ok_or("port needs a port number.")?);
+                }
+                "host" => {
+                    let host = args.next().and_then(|host| host.parse().ok());
+                    filter.host = Some(host.ok_or("host needs an IP address.")?);
+                }
+                _ => return Err("Unknown filter."),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, frame: &[u8]) -> bool {
+        if frame.len() < 14 {
+            return false;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let payload = &frame[14..];
+        let host = self.host.as_ref().map(address_bytes);
+        if ethertype == ETHERTYPE_ARP {
+            return self.port.is_none()
+                && matches!(self.protocol, None | Some(Protocol::Arp))
+                && host.map_or(true, |host| {
+                    payload.len() >= 28 && (&payload[14..18] == host || &payload[24..28] == host)
+                });
+        }
+        // IPv6 extension headers aren't followed; such packets match on addresses only.
+        let (protocol, source, destination, transport) = match ethertype {
+            ETHERTYPE_IPV4 if payload.len() >= 20 => {
+                let header_len = usize::from(payload[0] & 0xF) * 4;
+                (
+                    payload[9],
+                    &payload[12..16],
+                    &payload[16..20],
+                    payload.get(header_len..),
+                )
+            }
+            ETHERTYPE_IPV6 if payload.len() >= 40 => (
+                payload[6],
+                &payload[8..24],
+                &payload[24..40],
+                payload.get(40..),
+            ),
+            _ => return self.protocol.is_none() && self.port.is_none() && self.host.is_none(),
+        };
+        let protocol_matches = match self.protocol {
+            None => true,
+            Some(Protocol::Arp) => false,
+            Some(Protocol::Icmp) => protocol == 1 || protocol == 58,
+            Some(Protocol::Tcp) => protocol == 6,
+            Some(Protocol::Udp) => protocol == 17,
+        };
+        let host_matches = host.map_or(true, |host| source == host || destination == host);
+        let port_matches = match (self.port, transport) {
+            (None, _) => true,
+            (Some(port), Some(ports)) if (protocol == 6 || protocol == 17) && ports.len() >= 4 => {
+                let port = port.to_be_bytes();
+                ports[..2] == port || ports[2..4] == port
+            }
+            _ => false,
+        };
+        protocol_matches && host_matches && port_matches
+    }
+}
+
+enum Output {
+    File(String),
+    /// Hex dump between markers, since the serial port also carries the log.
+    Serial,
+}
+
+struct Capture {
+    filter: Filter,
+    output: Output,
+    data: Vec<u8>,
+    /// Frames that would grow `data` past this are dropped.
+    limit: usize,
+    packets: usize,
+    dropped: usize,
+}
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+}
+
+fn push_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Called by the device layer with every frame sent or received.
+pub fn record(frame: &[u8]) {
+    let mut capture = CAPTURE.lock();
+    let capture = match capture.as_mut() {
+        Some(capture) => capture,
+        None => return,
+    };
+    if !capture.filter.matches(frame) {
+        return;
+    }
+    if capture.data.len() + 16 + frame.len() > capture.limit {
+        capture.dropped += 1;
+        return;
+    }
+    // Readers expect Unix time; until the clock is set, uptime has to do.
+    let now = clock::now_millis().unwrap_or_else(uptime_millis);
+    push_u32(&mut capture.data, (now / 1000) as u32);
+    push_u32(&mut capture.data, (now % 1000 * 1000) as u32);
+    push_u32(&mut capture.data, frame.len() as u32);
+    push_u32(&mut capture.data, frame.len() as u32);
+    capture.data.extend_from_slice(frame);
+    capture.packets += 1;
+}
+
+fn start(output: Output, filter: Filter) -> Result<(), &'static str> {
+    // A file capture has to fit the file it ends up in.
+    let limit = match &output {
+        Output::File(path) => usize::min(vfs::max_file_size(path)?, MAX_CAPTURE_SIZE),
+        Output::Serial => MAX_CAPTURE_SIZE,
+    };
+    let mut capture = CAPTURE.lock();
+    if capture.is_some() {
+        return Err("A capture is already running.");
+    }
+    // The classic pcap file header, microsecond timestamps.
+    let mut data = Vec::new();
+    push_u32(&mut data, 0xA1B2_C3D4);
+    data.extend_from_slice(&2u16.to_le_bytes());
+    data.extend_from_slice(&4u16.to_le_bytes());
+    push_u32(&mut data, 0);
+    push_u32(&mut data, 0);
+    push_u32(&mut data, SNAPLEN);
+    push_u32(&mut data, LINKTYPE_ETHERNET);
+    *capture = Some(Capture {
+        filter,
+        output,
+        data,
+        limit,
+        packets: 0,
+        dropped: 0,
+    });
+    Ok(())
+}
+
+fn stop() -> Result<Capture, &'static str> {
+    CAPTURE.lock().take().ok_or("No capture running.")
+}
+
+/// `pcap start <file|serial> [filters]` records frames until `pcap stop`.
+/// Filters are `arp`, `icmp`, `tcp`, `udp`, `port <n>` and `host <ip>`. A
+/// serial capture comes out as hex; `xxd -r -p` turns it back into a file.
+/// `pcap stop serial` dumps any capture that way, e.g. one that could not be
+/// saved to its file.
+pub fn pcap(args: &[&str]) {
+    match args {
+        [] | ["status"] => match CAPTURE.lock().as_ref() {
+            Some(capture) => println!(
+                "pcap: {} packets, {} bytes captured, {} dropped",
+                capture.packets,
+                capture.data.len(),
+                capture.dropped
+            ),
+            None => println!("pcap: not capturing"),
+        },
+        ["start", target, filters @ ..] => {
+            let filter = match Filter::parse(filters) {
+                Ok(filter) => filter,
+                Err(err) => {
+                    println!("pcap: {}", err);
+                    return;
+                }
+            };
+            let output = match *target {
+                "serial" => Output::Serial,
+                path => Output::File(path.to_string()),
+            };
+            match start(output, filter) {
+                Ok(()) => println!("[OK] Capturing to {}.", target),
+                Err(err) => println!("pcap: {}", err),
+            }
+        }
+        ["stop"] | ["stop", "serial"] => {
+            let mut capture = match stop() {
+                Ok(capture) => capture,
+                Err(err) => {
+                    println!("pcap: {}", err);
+                    return;
+                }
+            };
+            if args.len() == 2 {
+                capture.output = Output::Serial;
+            }
+            if let Output::File(path) = &capture.output {
+                if let Err(err) = vfs::write(path, &capture.data) {
+                    println!("pcap: {}: {}", path, err);
+                    println!("pcap: Still capturing; `pcap stop serial` dumps it instead.");
+                    *CAPTURE.lock() = Some(capture);
+                    return;
+                }
+            }
+            let target = match &capture.output {
+                Output::File(path) => path.as_str(),
+                Output::Serial => {
+                    serial_println!("-----BEGIN PCAP-----");
+                    for line in capture.data.chunks(32) {
+                        let mut hex = String::with_capacity(64);
+                        for byte in line {
+                            hex.push_str(&format!("{:02x}", byte));
+                        }
+                        serial_println!("{}", hex);
+                    }
+                    serial_println!("-----END PCAP-----");
+                    "serial"
+                }
+            };
+            println!(
+                "[OK] {} packets ({} dropped), {} bytes written to {}.",
+                capture.packets,
+                capture.dropped,
+                capture.data.len(),
+                target
+            );
+        }
+        _ => {
+            println!("Usage: pcap [status | start <file|serial> [arp|icmp|tcp|udp] [port <n>] [host <ip>] | stop [serial]]");
+        }
+    }
+}
+
+#[test_case]
+fn test_filter_matches_udp_port() {
+    // Ethernet, then an IPv4 header from 10.0.2.15 to 10.0.2.3, then UDP 49152 -> 53.
+    let mut frame = [0u8; 42];
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    frame[14] = 0x45;
+    frame[23] = 17;
+    frame[26..30].copy_from_slice(&[10, 0, 2, 15]);
+    frame[30..34].copy_from_slice(&[10, 0, 2, 3]);
+    frame[34..36].copy_from_slice(&49152u16.to_be_bytes());
+    frame[36..38].copy_from_slice(&53u16.to_be_bytes());
+
+    let mut filter = Filter {
+        protocol: Some(Protocol::Udp),
+        port: Some(53),
+        host: None,
+    };
+    assert!(filter.matches(&frame));
+    filter.port = Some(80);
+    assert!(!filter.matches(&frame));
+    filter.protocol = Some(Protocol::Tcp);
+    filter.port = None;
+    assert!(!filter.matches(&frame));
+}
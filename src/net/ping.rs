@@ -0,0 +1,180 @@
+use alloc::vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    socket::{IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer},
+    wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, Ipv4Address},
+};
+
+use crate::{
+    interrupts::uptime_millis,
+    net::{
+        dns,
+        stack::{block_until, with_stack, Link},
+    },
+    println,
+};
+
+const DEFAULT_COUNT: u16 = 4;
+const INTERVAL_MS: u64 = 1000;
+const PAYLOAD_SIZE: usize = 56;
+/// The ICMP header on top of the payload, for the "64 bytes" line.
+const ICMP_HEADER_SIZE: usize = 8;
+
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(0x4B00);
+
+fn parse_args(args: &[&str]) -> Result<(&str, u16), &'static str> {
+    let mut target = None;
+    let mut count = DEFAULT_COUNT;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == "-c" {
+            count = args
+                .next()
+                .and_then(|count| count.parse().ok())
+                .filter(|count| *count > 0)
+                .ok_or("-c needs a positive count")?;
+        } else if target.is_none() {
+            target = Some(*arg);
+        } else {
+            return Err("too many arguments");
+        }
+    }
+    Ok((target.ok_or("missing target")?, count))
+}
+
+pub fn ping(args: &[&str]) {
+    let (target, count) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            println!("ping: {}", err);
+            println!("Usage: ping <address|host> [-c count]");
+            return;
+        }
+    };
+    let address = match dns::resolve_ipv4(target) {
+        Ok(address) => address,
+        Err(err) => {
+            println!("ping: {}: {}", target, err);
+            return;
+        }
+    };
+    if let Err(err) = run(target, address, count) {
+        println!("ping: {}", err);
+    }
+}
+
+fn run(target: &str, address: Ipv4Address, count: u16) -> Result<(), &'static str> {
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let link = Link::to(IpAddress::Ipv4(address));
+    let handle = with_stack(|stack| {
+        let rx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 4], vec![0; 512]);
+        let tx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; 4], vec![0; 512]);
+        let mut socket = IcmpSocket::new(rx_buffer, tx_buffer);
+        socket
+            .bind(IcmpEndpoint::Ident(ident))
+            .map(|_| stack.sockets.add(link, socket))
+            .map_err(|_| "Could not bind an ICMP socket.")
+    })??;
+
+    println!("PING {} ({}): {} data bytes", target, address, PAYLOAD_SIZE);
+    let checksum = ChecksumCapabilities::default();
+    let mut received = 0u32;
+    let (mut min, mut max, mut total) = (u64::MAX, 0u64, 0u64);
+    let mut result = Ok(());
+
+    for seq_no in 0..count {
+        let sent_at = uptime_millis();
+        let mut data = [0u8; PAYLOAD_SIZE];
+        data[..8].copy_from_slice(&sent_at.to_le_bytes());
+        let sent = with_stack(|stack| {
+            let mut socket = stack.sockets.get::<IcmpSocket>(handle);
+            let repr = Icmpv4Repr::EchoRequest {
+                ident,
+                seq_no,
+                data: &data,
+            };
+            let buffer = socket
+                .send(repr.buffer_len(), IpAddress::Ipv4(address))
+                .map_err(|_| "Send buffer full.")?;
+            repr.emit(&mut Icmpv4Packet::new_unchecked(buffer), &checksum);
+            Ok(())
+        })
+        .and_then(|sent| sent);
+        if let Err(err) = sent {
+            result = Err(err);
+            break;
+        }
+
+        let reply = block_until(INTERVAL_MS, |stack| {
+            let mut socket = stack.sockets.get::<IcmpSocket>(handle);
+            while socket.can_recv() {
+                let (payload, _) = socket.recv().ok()?;
+                let packet = Icmpv4Packet::new_checked(payload).ok()?;
+                if let Ok(Icmpv4Repr::EchoReply {
+                    ident: reply_ident,
+                    seq_no: reply_seq,
+                    data,
+                }) = Icmpv4Repr::parse(&packet, &checksum)
+                {
+                    if reply_ident == ident && reply_seq == seq_no && data.len() >= 8 {
+                        let mut stamp = [0u8; 8];
+                        stamp.copy_from_slice(&data[..8]);
+                        return Some((data.len(), u64::from_le_bytes(stamp)));
+                    }
+                }
+            }
+            None
+        });
+        match reply {
+            Ok(Some((length, stamp))) => {
+                let rtt = uptime_millis().saturating_sub(stamp);
+                received += 1;
+                min = min.min(rtt);
+                max = max.max(rtt);
+                total += rtt;
+                println!(
+                    "{} bytes from {}: icmp_seq={} time={} ms",
+                    length + ICMP_HEADER_SIZE,
+                    address,
+                    seq_no,
+                    rtt
+                );
+            }
+            Ok(None) => println!("Request timeout for icmp_seq {}", seq_no),
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        }
+        // Keep a one second rhythm even when the reply came back quickly.
+        if seq_no + 1 < count {
+            let elapsed = uptime_millis() - sent_at;
+            if elapsed < INTERVAL_MS {
+                let _ = block_until(INTERVAL_MS - elapsed, |_| None::<()>);
+            }
+        }
+    }
+
+    let _ = with_stack(|stack| stack.sockets.remove(handle));
+    result?;
+
+    let transmitted = u32::from(count);
+    println!("--- {} ping statistics ---", target);
+    println!(
+        "{} packets transmitted, {} received, {}% packet loss",
+        transmitted,
+        received,
+        (transmitted - received) * 100 / transmitted
+    );
+    if received > 0 {
+        println!(
+            "rtt min/avg/max = {}/{}/{} ms",
+            min,
+            total / u64::from(received),
+            max
+        );
+    }
+    Ok(())
+}
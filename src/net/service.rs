@@ -0,0 +1,111 @@
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    task::{Context, Poll},
+};
+
+use futures_util::{
+    future::{select, Either},
+    task::AtomicWaker,
+};
+
+use crate::{
+    net::{TcpListener, TcpStream},
+    println, task,
+};
+
+/// A TCP server running as a kernel task, started and stopped from the shell.
+/// Each accepted connection gets its own task; stopping closes the listener
+/// and leaves open connections to finish on their own.
+pub struct Service {
+    name: &'static str,
+    running: AtomicBool,
+    stopping: AtomicBool,
+    port: AtomicU16,
+    waker: AtomicWaker,
+}
+
+impl Service {
+    pub const fn new(name: &'static str) -> Self {
+        Service {
+            name,
+            running: AtomicBool::new(false),
+            stopping: AtomicBool::new(false),
+            port: AtomicU16::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// The port being listened on, if the service is running.
+    pub fn port(&self) -> Option<u16> {
+        if self.running.load(Ordering::SeqCst) {
+            Some(self.port.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    }
+
+    pub fn start<F, Fut>(&'static self, port: u16, handler: F) -> Result<(), &'static str>
+    where
+        F: Fn(TcpStream) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("Already running.");
+        }
+        let mut listener = match TcpListener::bind(port) {
+            Ok(listener) => listener,
+            Err(err) => {
+                self.running.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        self.stopping.store(false, Ordering::SeqCst);
+        self.port.store(port, Ordering::SeqCst);
+        task::spawn(async move {
+            loop {
+                match select(Box::pin(listener.accept()), Stopped(self)).await {
+                    Either::Left((Ok(stream), _)) => task::spawn(handler(stream)),
+                    Either::Left((Err(err), _)) => {
+                        println!("[FAIL] {}: {}", self.name, err);
+                        break;
+                    }
+                    Either::Right(_) => break,
+                }
+            }
+            drop(listener);
+            self.running.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    pub fn stop(&self) -> Result<(), &'static str> {
+        if !self.running.load(Ordering::SeqCst) {
+            return Err("Not running.");
+        }
+        self.stopping.store(true, Ordering::SeqCst);
+        self.waker.wake();
+        Ok(())
+    }
+}
+
+/// Resolves once [`Service::stop`] has been called.
+struct Stopped(&'static Service);
+
+impl Future for Stopped {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0.stopping.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        self.0.waker.register(cx.waker());
+        if self.0.stopping.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
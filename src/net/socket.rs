@@ -0,0 +1,463 @@
+use alloc::vec;
+use core::{
+    future::Future,
+    task::{Context, Poll, Waker},
+};
+
+use futures_util::future::poll_fn;
+use smoltcp::{
+    socket::{
+        TcpSocket as SmolTcpSocket, TcpSocketBuffer, TcpState, UdpPacketMetadata,
+        UdpSocket as SmolUdpSocket, UdpSocketBuffer,
+    },
+    time::Duration,
+    wire::IpEndpoint,
+};
+
+use crate::{
+    net::{
+        self,
+        stack::{ephemeral_port, with_stack, Handle, Link},
+    },
+    task::{job::block_on, timeout},
+};
+
+const TCP_BUFFER_SIZE: usize = 8192;
+const UDP_BUFFER_SIZE: usize = 4096;
+const UDP_PACKETS: usize = 8;
+/// Connections that stop answering are aborted after this long.
+const TCP_TIMEOUT_SECS: u64 = 10;
+/// Probes idle connections often enough that a quiet but healthy peer never
+/// hits the timeout.
+const TCP_KEEP_ALIVE_SECS: u64 = 4;
+
+fn new_tcp_socket() -> SmolTcpSocket<'static> {
+    let mut socket = SmolTcpSocket::new(
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    socket.set_timeout(Some(Duration::from_secs(TCP_TIMEOUT_SECS)));
+    socket.set_keep_alive(Some(Duration::from_secs(TCP_KEEP_ALIVE_SECS)));
+    socket
+}
+
+/// Runs `f` on the TCP socket behind `handle` from inside a future's `poll`.
+fn poll_tcp<T>(
+    handle: Handle,
+    cx: &mut Context,
+    f: impl FnOnce(&mut SmolTcpSocket, &Waker) -> Poll<Result<T, &'static str>>,
+) -> Poll<Result<T, &'static str>> {
+    match with_stack(|stack| f(&mut stack.sockets.get::<SmolTcpSocket>(handle), cx.waker())) {
+        Ok(poll) => poll,
+        Err(err) => Poll::Ready(Err(err)),
+    }
+}
+
+fn poll_udp<T>(
+    handle: Handle,
+    cx: &mut Context,
+    f: impl FnOnce(&mut SmolUdpSocket, &Waker) -> Poll<Result<T, &'static str>>,
+) -> Poll<Result<T, &'static str>> {
+    match with_stack(|stack| f(&mut stack.sockets.get::<SmolUdpSocket>(handle), cx.waker())) {
+        Ok(poll) => poll,
+        Err(err) => Poll::Ready(Err(err)),
+    }
+}
+
+/// Waits for `future` from a job, for at most `timeout_ms`.
+fn block_on_for<T>(
+    timeout_ms: u64,
+    future: impl Future<Output = Result<T, &'static str>>,
+) -> Result<T, &'static str> {
+    block_on(timeout(
+        core::time::Duration::from_millis(timeout_ms),
+        future,
+    ))??
+}
+
+/// A TCP connection for kernel tasks. Every operation is a future that parks on
+/// smoltcp's socket wakers until the network task has polled the interface.
+pub struct TcpStream {
+    handle: Handle,
+}
+
+impl TcpStream {
+    pub async fn connect(remote: IpEndpoint) -> Result<TcpStream, &'static str> {
+        let handle = with_stack(|stack| {
+            let mut socket = new_tcp_socket();
+            socket
+                .connect(remote, ephemeral_port())
+                .map(|_| stack.sockets.add(Link::to(remote.addr), socket))
+                .map_err(|_| "Could not open a TCP socket.")
+        })??;
+        // From here on dropping the stream cleans the socket up.
+        let stream = TcpStream { handle };
+        net::wake();
+        poll_fn(|cx| {
+            poll_tcp(handle, cx, |socket, waker| {
+                if socket.may_send() {
+                    Poll::Ready(Ok(()))
+                } else if !socket.is_active() {
+                    Poll::Ready(Err("Connection refused."))
+                } else {
+                    socket.register_send_waker(waker);
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> Result<IpEndpoint, &'static str> {
+        with_stack(|stack| {
+            stack
+                .sockets
+                .get::<SmolTcpSocket>(self.handle)
+                .remote_endpoint()
+        })
+    }
+
+    /// Reads whatever has arrived, waiting if nothing has. `Ok(0)` means the
+    /// peer closed its side.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let handle = self.handle;
+        let count = poll_fn(|cx| {
+            poll_tcp(handle, cx, |socket, waker| {
+                if socket.can_recv() {
+                    Poll::Ready(socket.recv_slice(buffer).map_err(|_| "Receiving failed."))
+                } else if !socket.may_recv() {
+                    Poll::Ready(Ok(0))
+                } else {
+                    socket.register_recv_waker(waker);
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+        // Freed buffer space means a window update for the peer.
+        net::wake();
+        Ok(count)
+    }
+
+    /// Queues as much of `data` as fits, waiting for room if the buffer is full.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        let handle = self.handle;
+        let count = poll_fn(|cx| {
+            poll_tcp(handle, cx, |socket, waker| {
+                if !socket.may_send() {
+                    Poll::Ready(Err("Connection closed."))
+                } else if socket.can_send() {
+                    Poll::Ready(socket.send_slice(data).map_err(|_| "Sending failed."))
+                } else {
+                    socket.register_send_waker(waker);
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+        net::wake();
+        Ok(count)
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), &'static str> {
+        while !data.is_empty() {
+            let count = self.write(data).await?;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Waits until everything written so far has been acknowledged.
+    pub async fn flush(&mut self) -> Result<(), &'static str> {
+        let handle = self.handle;
+        poll_fn(|cx| {
+            poll_tcp(handle, cx, |socket, waker| {
+                if socket.send_queue() == 0 {
+                    Poll::Ready(Ok(()))
+                } else if !socket.may_send() {
+                    Poll::Ready(Err("Connection closed."))
+                } else {
+                    socket.register_send_waker(waker);
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    /// Like [`connect`](Self::connect), but for synchronous callers such as
+    /// shell commands. Gives up after `timeout_ms`.
+    pub fn connect_blocking(
+        remote: IpEndpoint,
+        timeout_ms: u64,
+    ) -> Result<TcpStream, &'static str> {
+        block_on_for(timeout_ms, Self::connect(remote))
+    }
+
+    /// Like [`read`](Self::read), but for synchronous callers. Gives up after
+    /// `timeout_ms` without data.
+    pub fn read_blocking(
+        &mut self,
+        buffer: &mut [u8],
+        timeout_ms: u64,
+    ) -> Result<usize, &'static str> {
+        block_on_for(timeout_ms, self.read(buffer))
+    }
+
+    /// Like [`write_all`](Self::write_all), but for synchronous callers. A peer
+    /// that stops taking data runs into the socket's timeout.
+    pub fn write_all_blocking(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        block_on(self.write_all(data))?
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let handle = self.handle;
+        let _ = with_stack(|stack| stack.close_tcp(handle));
+        net::wake();
+    }
+}
+
+/// Accepts TCP connections on a port. smoltcp has no backlog, so one socket
+/// per interface listens at a time: it becomes the accepted stream and a
+/// fresh one takes over listening.
+pub struct TcpListener {
+    port: u16,
+    /// The listening sockets on eth0 and lo, `None` where listening again
+    /// after an accept failed.
+    handles: [Option<Handle>; 2],
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, &'static str> {
+        let ethernet = Self::listen(Link::Ethernet, port)?;
+        let loopback = match Self::listen(Link::Loopback, port) {
+            Ok(handle) => handle,
+            Err(err) => {
+                let _ = with_stack(|stack| stack.sockets.remove(ethernet));
+                return Err(err);
+            }
+        };
+        Ok(TcpListener {
+            port,
+            handles: [Some(ethernet), Some(loopback)],
+        })
+    }
+
+    fn listen(link: Link, port: u16) -> Result<Handle, &'static str> {
+        with_stack(|stack| {
+            let mut socket = new_tcp_socket();
+            // An idle listener must not time out.
+            socket.set_timeout(None);
+            socket
+                .listen(port)
+                .map(|_| stack.sockets.add(link, socket))
+                .map_err(|_| "Could not listen on that port.")
+        })?
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn accept(&mut self) -> Result<TcpStream, &'static str> {
+        let port = self.port;
+        for (slot, link) in self
+            .handles
+            .iter_mut()
+            .zip([Link::Ethernet, Link::Loopback])
+        {
+            if slot.is_none() {
+                *slot = Self::listen(link, port).ok();
+            }
+        }
+        if self.handles.iter().all(Option::is_none) {
+            return Err("Could not listen on that port.");
+        }
+        let handles = self.handles;
+        let (index, handle) = poll_fn(|cx| {
+            for (index, &handle) in handles.iter().enumerate() {
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                let poll = poll_tcp(handle, cx, |socket, waker| {
+                    if socket.may_send() {
+                        Poll::Ready(Ok((index, handle)))
+                    } else {
+                        if !socket.is_open() {
+                            // Not listening anymore; the peer aborted the handshake.
+                            let _ = socket.listen(port);
+                        }
+                        socket.register_recv_waker(waker);
+                        socket.register_send_waker(waker);
+                        Poll::Pending
+                    }
+                });
+                if poll.is_ready() {
+                    return poll;
+                }
+            }
+            Poll::Pending
+        })
+        .await?;
+        // The accepted socket belongs to the stream now, whether or not a new
+        // one can listen in its place; the next accept tries again.
+        self.handles[index] = Self::listen(handle.link(), port).ok();
+        let _ = with_stack(|stack| {
+            stack
+                .sockets
+                .get::<SmolTcpSocket>(handle)
+                .set_timeout(Some(Duration::from_secs(TCP_TIMEOUT_SECS)))
+        });
+        Ok(TcpStream { handle })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let handles = self.handles;
+        let _ = with_stack(|stack| {
+            for handle in handles.iter().flatten().copied() {
+                if stack.sockets.get::<SmolTcpSocket>(handle).state() == TcpState::Listen {
+                    stack.sockets.remove(handle);
+                } else {
+                    stack.close_tcp(handle);
+                }
+            }
+        });
+    }
+}
+
+/// A UDP port, bound on both interfaces. Datagrams go out through the one
+/// that reaches their destination.
+pub struct UdpSocket {
+    /// The bound sockets on eth0 and lo.
+    handles: [Handle; 2],
+}
+
+impl UdpSocket {
+    /// Binds a UDP socket to `port`, or to an ephemeral port when `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, &'static str> {
+        let port = if port == 0 { ephemeral_port() } else { port };
+        let ethernet = Self::bind_on(Link::Ethernet, port)?;
+        let loopback = match Self::bind_on(Link::Loopback, port) {
+            Ok(handle) => handle,
+            Err(err) => {
+                let _ = with_stack(|stack| stack.sockets.remove(ethernet));
+                return Err(err);
+            }
+        };
+        Ok(UdpSocket {
+            handles: [ethernet, loopback],
+        })
+    }
+
+    fn bind_on(link: Link, port: u16) -> Result<Handle, &'static str> {
+        with_stack(|stack| {
+            let mut socket = SmolUdpSocket::new(
+                UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; UDP_PACKETS],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+                UdpSocketBuffer::new(
+                    vec![UdpPacketMetadata::EMPTY; UDP_PACKETS],
+                    vec![0; UDP_BUFFER_SIZE],
+                ),
+            );
+            socket
+                .bind(port)
+                .map(|_| stack.sockets.add(link, socket))
+                .map_err(|_| "Could not bind a UDP socket.")
+        })?
+    }
+
+    pub fn local_port(&self) -> Result<u16, &'static str> {
+        with_stack(|stack| {
+            stack
+                .sockets
+                .get::<SmolUdpSocket>(self.handles[0])
+                .endpoint()
+                .port
+        })
+    }
+
+    pub async fn send_to(&self, data: &[u8], remote: IpEndpoint) -> Result<(), &'static str> {
+        let handle = match Link::to(remote.addr) {
+            Link::Ethernet => self.handles[0],
+            Link::Loopback => self.handles[1],
+        };
+        poll_fn(|cx| {
+            poll_udp(handle, cx, |socket, waker| {
+                if socket.can_send() {
+                    Poll::Ready(
+                        socket
+                            .send_slice(data, remote)
+                            .map_err(|_| "Datagram too large."),
+                    )
+                } else {
+                    socket.register_send_waker(waker);
+                    Poll::Pending
+                }
+            })
+        })
+        .await?;
+        net::wake();
+        Ok(())
+    }
+
+    /// Waits for the next datagram on either interface and returns its length
+    /// and sender.
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, IpEndpoint), &'static str> {
+        let handles = self.handles;
+        poll_fn(|cx| {
+            for &handle in handles.iter() {
+                let poll = poll_udp(handle, cx, |socket, waker| {
+                    if socket.can_recv() {
+                        Poll::Ready(socket.recv_slice(buffer).map_err(|_| "Receiving failed."))
+                    } else {
+                        socket.register_recv_waker(waker);
+                        Poll::Pending
+                    }
+                });
+                if poll.is_ready() {
+                    return poll;
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Like [`send_to`](Self::send_to), but for synchronous callers.
+    pub fn send_to_blocking(&self, data: &[u8], remote: IpEndpoint) -> Result<(), &'static str> {
+        block_on(self.send_to(data, remote))?
+    }
+
+    /// Like [`recv_from`](Self::recv_from), but for synchronous callers.
+    /// `Ok(None)` means nothing arrived within `timeout_ms`.
+    pub fn recv_from_blocking(
+        &self,
+        buffer: &mut [u8],
+        timeout_ms: u64,
+    ) -> Result<Option<(usize, IpEndpoint)>, &'static str> {
+        let duration = core::time::Duration::from_millis(timeout_ms);
+        match block_on(timeout(duration, self.recv_from(buffer)))? {
+            Ok(received) => received.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let handles = self.handles;
+        let _ = with_stack(|stack| {
+            for handle in handles {
+                stack.sockets.remove(handle);
+            }
+        });
+    }
+}
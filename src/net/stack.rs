@@ -0,0 +1,650 @@
+use alloc::{collections::BTreeMap, format, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use smoltcp::{
+    dhcp::Dhcpv4Client,
+    iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes},
+    socket::{
+        AnySocket, RawPacketMetadata, RawSocketBuffer, Socket, SocketHandle, SocketRef, SocketSet,
+        TcpSocket, TcpState,
+    },
+    time::Instant,
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address},
+};
+use spin::Mutex;
+
+use crate::{
+    interrupts::uptime_millis,
+    net::{
+        self,
+        loopback::{self, Loopback},
+        take_device, NicDevice,
+    },
+    println,
+    task::job,
+};
+
+pub const INTERFACE_NAME: &str = "eth0";
+pub const LOOPBACK_NAME: &str = "lo";
+
+/// Give DHCP this long before falling back to QEMU's user networking defaults.
+const DHCP_FALLBACK_MS: u64 = 10_000;
+const FALLBACK_ADDRESS: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+const FALLBACK_PREFIX: u8 = 24;
+const FALLBACK_GATEWAY: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+const FALLBACK_DNS: Ipv4Address = Ipv4Address([10, 0, 2, 3]);
+
+/// How long smoltcp keeps a neighbor it hasn't heard from again.
+const NEIGHBOR_LIFETIME_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSource {
+    Unconfigured,
+    Dhcp,
+    Static,
+}
+
+impl AddressSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressSource::Unconfigured => "unconfigured",
+            AddressSource::Dhcp => "dhcp",
+            AddressSource::Static => "static",
+        }
+    }
+}
+
+/// The interface a socket sends and receives through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Ethernet,
+    Loopback,
+}
+
+impl Link {
+    /// The interface that reaches `address`.
+    pub fn to(address: IpAddress) -> Link {
+        let loopback = match address {
+            IpAddress::Ipv4(address) => address.is_loopback(),
+            IpAddress::Ipv6(address) => address.is_loopback(),
+            _ => false,
+        };
+        if loopback {
+            Link::Loopback
+        } else {
+            Link::Ethernet
+        }
+    }
+}
+
+/// A socket in [`Sockets`], together with the interface it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle {
+    link: Link,
+    handle: SocketHandle,
+}
+
+impl Handle {
+    pub fn link(&self) -> Link {
+        self.link
+    }
+}
+
+/// One socket set per interface. smoltcp sends a socket's packets through
+/// whichever interface polls it, so each interface only polls its own.
+pub struct Sockets {
+    ethernet: SocketSet<'static>,
+    loopback: SocketSet<'static>,
+}
+
+impl Sockets {
+    fn set(&mut self, link: Link) -> &mut SocketSet<'static> {
+        match link {
+            Link::Ethernet => &mut self.ethernet,
+            Link::Loopback => &mut self.loopback,
+        }
+    }
+
+    pub fn add<T: Into<Socket<'static>>>(&mut self, link: Link, socket: T) -> Handle {
+        Handle {
+            link,
+            handle: self.set(link).add(socket),
+        }
+    }
+
+    pub fn get<T: AnySocket<'static>>(&mut self, handle: Handle) -> SocketRef<T> {
+        self.set(handle.link).get(handle.handle)
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Socket<'static> {
+        self.set(handle.link).remove(handle.handle)
+    }
+}
+
+pub struct NetStack {
+    pub iface: EthernetInterface<'static, NicDevice>,
+    pub lo: EthernetInterface<'static, Loopback>,
+    pub sockets: Sockets,
+    dhcp: Dhcpv4Client,
+    use_dhcp: bool,
+    pub source: AddressSource,
+    pub dns_servers: Vec<Ipv4Address>,
+    started_at: u64,
+    /// Closed TCP sockets still finishing their FIN exchange.
+    closing: Vec<Handle>,
+}
+
+/// A copy of eth0's IPv4 neighbor cache. smoltcp gives no access to its own,
+/// so this one is filled from the same frames by the same rules and expires
+/// entries as it does. Changes go the other way: the interface is rebuilt with
+/// a cache filled from this one.
+struct Neighbors {
+    /// eth0's network; smoltcp learns from IP packets only within it.
+    network: Option<Ipv4Cidr>,
+    /// MAC address and uptime when last heard from.
+    entries: BTreeMap<Ipv4Address, (EthernetAddress, u64)>,
+}
+
+lazy_static! {
+    pub static ref STACK: Mutex<Option<NetStack>> = Mutex::new(None);
+    static ref NEIGHBORS: Mutex<Neighbors> = Mutex::new(Neighbors {
+        network: None,
+        entries: BTreeMap::new(),
+    });
+}
+
+/// Uptime in milliseconds of the next time the stack asked to be polled.
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Hands out local ports for outgoing UDP and TCP, cycling through the IANA dynamic range.
+pub fn ephemeral_port() -> u16 {
+    let port = NEXT_EPHEMERAL_PORT.fetch_add(1, Ordering::Relaxed);
+    if port == u16::MAX {
+        NEXT_EPHEMERAL_PORT.store(EPHEMERAL_PORT_START, Ordering::Relaxed);
+    }
+    port
+}
+
+pub fn now() -> Instant {
+    Instant::from_millis(uptime_millis() as i64)
+}
+
+/// eth0 around `device`, with no default route yet.
+fn ethernet_interface(
+    device: NicDevice,
+    neighbor_cache: NeighborCache<'static>,
+    ip_addrs: Vec<IpCidr>,
+) -> EthernetInterface<'static, NicDevice> {
+    let mac = device.mac_address();
+    EthernetInterfaceBuilder::new(device)
+        .ethernet_addr(EthernetAddress(mac))
+        .neighbor_cache(neighbor_cache)
+        .ip_addrs(ip_addrs)
+        .routes(Routes::new(BTreeMap::new()))
+        .finalize()
+}
+
+/// Brings up `lo`, plus `eth0` on the first detected card, and starts the network task.
+pub fn init() {
+    let device = take_device();
+    let has_nic = device.nic().is_some();
+    // eth0's address is filled in by DHCP or ifconfig.
+    let iface = ethernet_interface(
+        device,
+        NeighborCache::new(BTreeMap::new()),
+        vec![IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)],
+    );
+    let lo = EthernetInterfaceBuilder::new(Loopback::new())
+        .ethernet_addr(EthernetAddress(loopback::MAC_ADDRESS))
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![
+            IpCidr::new(Ipv4Address::new(127, 0, 0, 1).into(), 8),
+            IpCidr::new(Ipv6Address::LOOPBACK.into(), 128),
+        ])
+        .finalize();
+    let mut sockets = Sockets {
+        ethernet: SocketSet::new(Vec::new()),
+        loopback: SocketSet::new(Vec::new()),
+    };
+    let dhcp_rx_buffer = RawSocketBuffer::new([RawPacketMetadata::EMPTY; 1], vec![0; 900]);
+    let dhcp_tx_buffer = RawSocketBuffer::new([RawPacketMetadata::EMPTY; 1], vec![0; 600]);
+    let dhcp = Dhcpv4Client::new(&mut sockets.ethernet, dhcp_rx_buffer, dhcp_tx_buffer, now());
+
+    *STACK.lock() = Some(NetStack {
+        iface,
+        lo,
+        sockets,
+        dhcp,
+        use_dhcp: has_nic,
+        source: AddressSource::Unconfigured,
+        dns_servers: Vec::new(),
+        started_at: uptime_millis(),
+        closing: Vec::new(),
+    });
+    println!("[OK] {} up at 127.0.0.1/8 and ::1/128.", LOOPBACK_NAME);
+    if has_nic {
+        println!(
+            "[OK] {} up, requesting an address over DHCP.",
+            INTERFACE_NAME
+        );
+    } else {
+        println!(
+            "[INFO] No network card found, only {} is up.",
+            LOOPBACK_NAME
+        );
+    }
+    crate::task::spawn(network_task());
+    net::ntp::init();
+}
+
+impl NetStack {
+    pub fn ipv4_cidr(&self) -> Option<Ipv4Cidr> {
+        self.iface.ip_addrs().iter().find_map(|cidr| match cidr {
+            IpCidr::Ipv4(cidr) if !cidr.address().is_unspecified() => Some(*cidr),
+            _ => None,
+        })
+    }
+
+    pub fn ipv4_address(&self) -> Option<Ipv4Address> {
+        self.ipv4_cidr().map(|cidr| cidr.address())
+    }
+
+    pub fn set_ipv4_cidr(&mut self, cidr: Ipv4Cidr) {
+        self.iface.update_ip_addrs(|addrs| {
+            if let Some(addr) = addrs.iter_mut().next() {
+                *addr = IpCidr::Ipv4(cidr);
+            }
+        });
+        NEIGHBORS.lock().network = Some(cidr).filter(|cidr| !cidr.address().is_unspecified());
+    }
+
+    pub fn default_gateway(&mut self) -> Option<Ipv4Address> {
+        let mut gateway = None;
+        self.iface.routes_mut().update(|routes| {
+            let default = IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0);
+            if let Some(route) = routes.get(&default) {
+                if let IpAddress::Ipv4(address) = route.via_router {
+                    gateway = Some(address);
+                }
+            }
+        });
+        gateway
+    }
+
+    pub fn set_default_gateway(&mut self, gateway: Option<Ipv4Address>) {
+        let routes = self.iface.routes_mut();
+        match gateway {
+            Some(gateway) => {
+                let _ = routes.add_default_ipv4_route(gateway);
+            }
+            None => routes.update(|routes| {
+                routes.remove(&IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0));
+            }),
+        }
+    }
+
+    /// Pins the configuration and stops listening to DHCP.
+    pub fn configure_static(&mut self, cidr: Ipv4Cidr, gateway: Option<Ipv4Address>) {
+        self.use_dhcp = false;
+        self.set_ipv4_cidr(cidr);
+        // No gateway means none, not the one DHCP handed out.
+        self.set_default_gateway(gateway);
+        self.source = AddressSource::Static;
+    }
+
+    /// Drops the current address and starts a fresh DHCP discovery.
+    pub fn restart_dhcp(&mut self) {
+        self.use_dhcp = true;
+        self.started_at = uptime_millis();
+        self.source = AddressSource::Unconfigured;
+        self.set_ipv4_cidr(Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
+        self.set_default_gateway(None);
+        self.dhcp.reset(now());
+    }
+
+    /// Makes eth0's neighbor cache what [`NEIGHBORS`] holds. smoltcp can't
+    /// edit its cache from outside, so the interface is rebuilt around the
+    /// same card, addresses and default route; sockets live in their own set
+    /// and carry on.
+    fn reload_neighbors(&mut self) {
+        let mut cache = NeighborCache::new(BTreeMap::new());
+        for (ip, (mac, seen)) in &NEIGHBORS.lock().entries {
+            cache.fill((*ip).into(), *mac, Instant::from_millis(*seen as i64));
+        }
+        let gateway = self.default_gateway();
+        let ip_addrs = self.iface.ip_addrs().to_vec();
+        let device = NicDevice::new(self.iface.device_mut().take_nic());
+        self.iface = ethernet_interface(device, cache, ip_addrs);
+        self.set_default_gateway(gateway);
+    }
+
+    /// Closes a TCP socket and frees it once the connection has wound down.
+    pub fn close_tcp(&mut self, handle: Handle) {
+        self.sockets.get::<TcpSocket>(handle).close();
+        self.closing.push(handle);
+    }
+
+    fn reap_closed(&mut self) {
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let state = sockets.get::<TcpSocket>(handle).state();
+            let done = matches!(state, TcpState::Closed | TcpState::TimeWait);
+            if done {
+                sockets.remove(handle);
+            }
+            !done
+        });
+    }
+
+    pub fn poll(&mut self) {
+        let timestamp = now();
+        // Errors here are single bad packets; smoltcp drops them and carries on.
+        let _ = self.iface.poll(&mut self.sockets.ethernet, timestamp);
+        let _ = self.lo.poll(&mut self.sockets.loopback, timestamp);
+        self.reap_closed();
+        if self.use_dhcp {
+            self.poll_dhcp(timestamp);
+        }
+        let delay = [
+            self.iface.poll_delay(&self.sockets.ethernet, timestamp),
+            self.lo.poll_delay(&self.sockets.loopback, timestamp),
+        ]
+        .iter()
+        .flatten()
+        .map(|delay| delay.total_millis())
+        .min()
+        .unwrap_or(1000);
+        let delay = if self.use_dhcp {
+            u64::min(delay, self.dhcp.next_poll(timestamp).total_millis())
+        } else {
+            delay
+        };
+        NEXT_POLL.store(uptime_millis() + delay, Ordering::Relaxed);
+    }
+
+    fn poll_dhcp(&mut self, timestamp: Instant) {
+        let config = self
+            .dhcp
+            .poll(&mut self.iface, &mut self.sockets.ethernet, timestamp)
+            .unwrap_or(None);
+        if let Some(config) = config {
+            if let Some(cidr) = config.address {
+                if self.ipv4_cidr() != Some(cidr) {
+                    self.set_ipv4_cidr(cidr);
+                    println!("[OK] DHCP: {} is {}", INTERFACE_NAME, cidr);
+                }
+                self.source = AddressSource::Dhcp;
+            }
+            if let Some(router) = config.router {
+                self.set_default_gateway(Some(router));
+            }
+            let servers: Vec<Ipv4Address> = config.dns_servers.iter().flatten().copied().collect();
+            if !servers.is_empty() {
+                self.dns_servers = servers;
+            }
+        }
+        if self.source == AddressSource::Unconfigured
+            && uptime_millis() - self.started_at > DHCP_FALLBACK_MS
+        {
+            let cidr = Ipv4Cidr::new(FALLBACK_ADDRESS, FALLBACK_PREFIX);
+            println!(
+                "[INFO] DHCP timed out, falling back to {} via {}",
+                cidr, FALLBACK_GATEWAY
+            );
+            self.set_ipv4_cidr(cidr);
+            self.set_default_gateway(Some(FALLBACK_GATEWAY));
+            if self.dns_servers.is_empty() {
+                self.dns_servers.push(FALLBACK_DNS);
+            }
+            // DHCP keeps running and takes over if a server shows up later.
+            self.source = AddressSource::Static;
+        }
+    }
+}
+
+/// Runs `f` on the stack, or fails when no interface is up.
+pub fn with_stack<T>(f: impl FnOnce(&mut NetStack) -> T) -> Result<T, &'static str> {
+    STACK
+        .lock()
+        .as_mut()
+        .map(f)
+        .ok_or("Networking is not available.")
+}
+
+/// Polls the stack until `f` returns a value or `timeout_ms` pass. Lets shell
+/// commands wait for the network without returning to the executor; they run
+/// as [`Job`](crate::task::job::Job)s, so other tasks run while they wait.
+/// Anywhere else waiting would stall every task, so it fails instead.
+pub fn block_until<T>(
+    timeout_ms: u64,
+    mut f: impl FnMut(&mut NetStack) -> Option<T>,
+) -> Result<Option<T>, &'static str> {
+    if !job::in_job() {
+        return Err("Can only wait in a job.");
+    }
+    let deadline = uptime_millis() + timeout_ms;
+    loop {
+        if let Some(value) = with_stack(|stack| {
+            stack.poll();
+            f(stack)
+        })? {
+            return Ok(Some(value));
+        }
+        if uptime_millis() >= deadline {
+            return Ok(None);
+        }
+        job::suspend();
+    }
+}
+
+pub fn poll() {
+    if let Some(stack) = STACK.lock().as_mut() {
+        stack.poll();
+    }
+}
+
+/// Called from the timer interrupt; wakes the network task once a poll is due.
+pub fn timer_tick(uptime: u64) {
+    let next = NEXT_POLL.load(Ordering::Relaxed);
+    if next != 0 && uptime >= next {
+        NEXT_POLL.store(0, Ordering::Relaxed);
+        net::wake();
+    }
+}
+
+/// Polls the interface whenever a card interrupts or a smoltcp timer runs out.
+async fn network_task() {
+    loop {
+        poll();
+        net::next_event().await;
+    }
+}
+
+/// Learns neighbors from a frame eth0 received, as smoltcp's cache does: the
+/// sender of every ARP packet, and the sender of IPv4 packets from within
+/// eth0's network.
+pub fn observe_frame(frame: &[u8]) {
+    const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+    const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+    if frame.len() < 34 {
+        return;
+    }
+    let (mac, ip) = match frame[12..14] {
+        ETHERTYPE_ARP if frame.len() >= 42 => (&frame[22..28], &frame[28..32]),
+        ETHERTYPE_IPV4 => (&frame[6..12], &frame[26..30]),
+        _ => return,
+    };
+    let mac = EthernetAddress::from_bytes(mac);
+    let ip = Ipv4Address::from_bytes(ip);
+    if !mac.is_unicast() || !ip.is_unicast() {
+        return;
+    }
+    let mut neighbors = NEIGHBORS.lock();
+    let in_network = neighbors
+        .network
+        .map_or(false, |cidr| cidr.contains_addr(&ip));
+    if frame[12..14] == ETHERTYPE_ARP || in_network {
+        neighbors.entries.insert(ip, (mac, uptime_millis()));
+    }
+}
+
+/// eth0's neighbors with the milliseconds since each was last heard from.
+pub fn arp_entries() -> Vec<(Ipv4Address, EthernetAddress, u64)> {
+    let uptime = uptime_millis();
+    let mut neighbors = NEIGHBORS.lock();
+    neighbors
+        .entries
+        .retain(|_, (_, seen)| uptime - *seen < NEIGHBOR_LIFETIME_MS);
+    neighbors
+        .entries
+        .iter()
+        .map(|(ip, (mac, seen))| (*ip, *mac, uptime - seen))
+        .collect()
+}
+
+fn format_mac(mac: EthernetAddress) -> String {
+    let b = mac.0;
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5]
+    )
+}
+
+pub fn ifconfig(args: &[&str]) {
+    let args = match args.first() {
+        Some(&INTERFACE_NAME) => &args[1..],
+        _ => args,
+    };
+    let result = with_stack(|stack| match args {
+        [] => {
+            if let Some(nic) = stack.iface.device().nic() {
+                println!(
+                    "{}: {}  HWaddr {}  link {}",
+                    INTERFACE_NAME,
+                    nic.name(),
+                    format_mac(stack.iface.ethernet_addr()),
+                    if nic.link_up() { "up" } else { "down" }
+                );
+                match stack.ipv4_cidr() {
+                    Some(cidr) => println!("      inet {}  ({})", cidr, stack.source.as_str()),
+                    None => println!("      no address ({})", stack.source.as_str()),
+                }
+                for server in &stack.dns_servers {
+                    println!("      dns {}", server);
+                }
+            }
+            println!("{}: loopback", LOOPBACK_NAME);
+            println!("      inet 127.0.0.1/8");
+            println!("      inet6 ::1/128");
+        }
+        _ if stack.iface.device().nic().is_none() => {
+            println!("ifconfig: {}: no network card", INTERFACE_NAME);
+        }
+        ["dhcp"] => {
+            stack.restart_dhcp();
+            println!("{}: requesting an address over DHCP.", INTERFACE_NAME);
+        }
+        [cidr] | [cidr, _] => {
+            let cidr = match cidr.parse::<Ipv4Cidr>() {
+                Ok(cidr) => cidr,
+                Err(_) => {
+                    println!("ifconfig: {}: expected an address like 10.0.2.15/24", cidr);
+                    return;
+                }
+            };
+            let gateway = match args.get(1).map(|gateway| gateway.parse::<Ipv4Address>()) {
+                Some(Ok(gateway)) => Some(gateway),
+                Some(Err(_)) => {
+                    println!("ifconfig: {}: not an IPv4 address", args[1]);
+                    return;
+                }
+                None => None,
+            };
+            stack.configure_static(cidr, gateway);
+            println!("{}: inet {} (static)", INTERFACE_NAME, cidr);
+        }
+        _ => println!("Usage: ifconfig [eth0] [<address>/<prefix> [gateway] | dhcp]"),
+    });
+    if let Err(err) = result {
+        println!("ifconfig: {}", err);
+    }
+}
+
+pub fn route(args: &[&str]) {
+    let result = with_stack(|stack| match args {
+        [] => {
+            println!("Destination         Gateway");
+            if let Some(cidr) = stack.ipv4_cidr() {
+                // smoltcp's Display impls ignore width, so pad the formatted string.
+                let network = format!("{}", cidr.network());
+                println!("{:<19} {} (on link)", network, INTERFACE_NAME);
+            }
+            match stack.default_gateway() {
+                Some(gateway) => println!("{:<19} {}", "default", gateway),
+                None => println!("{:<19} none", "default"),
+            }
+        }
+        ["add", "default", gateway] => match gateway.parse::<Ipv4Address>() {
+            Ok(gateway) => stack.set_default_gateway(Some(gateway)),
+            Err(_) => println!("route: {}: not an IPv4 address", gateway),
+        },
+        ["del", "default"] => stack.set_default_gateway(None),
+        _ => println!("Usage: route [add default <gateway> | del default]"),
+    });
+    if let Err(err) = result {
+        println!("route: {}", err);
+    }
+}
+
+fn reload_neighbors() {
+    if let Err(err) = with_stack(|stack| stack.reload_neighbors()) {
+        println!("arp: {}", err);
+    }
+}
+
+/// `arp` lists eth0's neighbor cache, `arp -d <address>` drops one entry and
+/// `arp -f` all of them, so the next packet to them asks over ARP again.
+pub fn arp(args: &[&str]) {
+    match args {
+        [] => {}
+        ["-f"] => {
+            NEIGHBORS.lock().entries.clear();
+            reload_neighbors();
+            return;
+        }
+        ["-d", address] => {
+            let address = match address.parse::<Ipv4Address>() {
+                Ok(address) => address,
+                Err(_) => {
+                    println!("arp: {}: not an IPv4 address", address);
+                    return;
+                }
+            };
+            let removed = NEIGHBORS.lock().entries.remove(&address).is_some();
+            if removed {
+                reload_neighbors();
+            } else {
+                println!("arp: {}: no entry", address);
+            }
+            return;
+        }
+        _ => {
+            println!("Usage: arp [-d <address> | -f]");
+            return;
+        }
+    }
+    let entries = arp_entries();
+    if entries.is_empty() {
+        println!("ARP table is empty.");
+        return;
+    }
+    println!("Address          HWaddress          Age");
+    for (ip, mac, age) in entries {
+        let ip = format!("{}", ip);
+        println!("{:<16} {}  {}s", ip, format_mac(mac), age / 1000);
+    }
+}
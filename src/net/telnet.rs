@@ -0,0 +1,358 @@
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{
+    command_dispatcher::dispatch_command,
+    interrupts::set_line_reader,
+    net::{service::Service, TcpStream},
+    println,
+    task::{
+        self,
+        job::{self, Job},
+    },
+    vga_buffer::{set_output_stream, Stream},
+};
+
+const DEFAULT_PORT: u16 = 23;
+/// How long a command waiting for input lets the remote user think.
+const INPUT_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+const MAX_LOGIN_ATTEMPTS: usize = 3;
+const MAX_LINE_LENGTH: usize = 1024;
+
+// Telnet commands and options, RFC 854, 857 and 858.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+
+static SERVICE: Service = Service::new("telnetd");
+
+lazy_static! {
+    /// Asked for at the start of every new session, if set.
+    static ref PASSWORD: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    /// After `IAC`.
+    Command,
+    /// After `IAC WILL`, `WONT`, `DO` or `DONT`, waiting for the option.
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// One telnet connection. The client's line is edited here: we tell it we
+/// echo, so it sends keystrokes one by one and shows only what we send back.
+struct Connection {
+    stream: TcpStream,
+    received: VecDeque<u8>,
+    state: State,
+    line: Vec<u8>,
+    after_cr: bool,
+    closed: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            received: VecDeque::new(),
+            state: State::Data,
+            line: Vec::new(),
+            after_cr: false,
+            closed: false,
+        }
+    }
+
+    /// Consumes received bytes until a line is complete. Echoes and replies to
+    /// option negotiation go into `reply`.
+    fn process(&mut self, echo: bool, reply: &mut Vec<u8>) -> Option<String> {
+        while let Some(byte) = self.received.pop_front() {
+            match self.state {
+                State::Data => {
+                    let after_cr = core::mem::replace(&mut self.after_cr, false);
+                    match byte {
+                        IAC => self.state = State::Command,
+                        // Clients end lines with CR LF or CR NUL.
+                        b'\n' if after_cr => {}
+                        b'\r' | b'\n' => {
+                            self.after_cr = byte == b'\r';
+                            reply.extend_from_slice(b"\r\n");
+                            let line = String::from_utf8_lossy(&self.line).into_owned();
+                            self.line.clear();
+                            return Some(line);
+                        }
+                        0x08 | 0x7F => {
+                            if self.line.pop().is_some() && echo {
+                                reply.extend_from_slice(b"\x08 \x08");
+                            }
+                        }
+                        0x20..=0x7E | 0x80..=0xFE if self.line.len() < MAX_LINE_LENGTH => {
+                            self.line.push(byte);
+                            if echo {
+                                reply.push(byte);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                State::Command => {
+                    self.state = match byte {
+                        WILL | WONT | DO | DONT => State::Option(byte),
+                        SB => State::Subnegotiation,
+                        // Anything else, e.g. `IAC NOP` or an escaped 0xFF, is ignored.
+                        _ => State::Data,
+                    }
+                }
+                State::Option(command) => {
+                    self.state = State::Data;
+                    negotiate(command, byte, reply);
+                }
+                State::Subnegotiation => {
+                    if byte == IAC {
+                        self.state = State::SubnegotiationIac;
+                    }
+                }
+                State::SubnegotiationIac => {
+                    self.state = if byte == SE {
+                        State::Data
+                    } else {
+                        State::Subnegotiation
+                    };
+                }
+            }
+        }
+        None
+    }
+
+    async fn read_line(&mut self, echo: bool) -> Option<String> {
+        loop {
+            let mut reply = Vec::new();
+            let line = self.process(echo, &mut reply);
+            if !reply.is_empty() && self.stream.write_all(&reply).await.is_err() {
+                break;
+            }
+            if line.is_some() {
+                return line;
+            }
+            let mut buffer = [0u8; 256];
+            match self.stream.read(&mut buffer).await {
+                Ok(count) if count > 0 => self.received.extend(&buffer[..count]),
+                _ => break,
+            }
+        }
+        self.closed = true;
+        None
+    }
+
+    async fn write_text(&mut self, text: &str) {
+        if self.stream.write_all(&to_network(text)).await.is_err() {
+            self.closed = true;
+        }
+    }
+}
+
+/// Telnet wants CR LF line endings.
+fn to_network(text: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte == b'\n' {
+            data.push(b'\r');
+        }
+        data.push(byte);
+    }
+    data
+}
+
+/// We offer to echo and to suppress go-ahead and refuse every other option.
+fn negotiate(command: u8, option: u8, reply: &mut Vec<u8>) {
+    let ours = option == OPTION_ECHO || option == OPTION_SUPPRESS_GO_AHEAD;
+    match command {
+        DO if !ours => reply.extend_from_slice(&[IAC, WONT, option]),
+        WILL if option == OPTION_SUPPRESS_GO_AHEAD => reply.extend_from_slice(&[IAC, DO, option]),
+        WILL => reply.extend_from_slice(&[IAC, DONT, option]),
+        _ => {}
+    }
+}
+
+/// The lines a command running for a session reads, and whether it is
+/// waiting for one.
+#[derive(Default)]
+struct Input {
+    lines: VecDeque<String>,
+    wanted: bool,
+    /// No more lines will come; the command gets empty ones.
+    closed: bool,
+}
+
+/// Runs one shell command as a job with its own output and input streams.
+/// Whenever it waits, for a line or for the network, what it printed so far
+/// goes to the client and the executor runs other tasks.
+async fn run_command(connection: &mut Connection, line: &str) {
+    let output = Stream::default();
+    let input = Arc::new(Mutex::new(Input::default()));
+    let (job_output, job_input, line) = (output.clone(), input.clone(), line.to_string());
+    let mut job = Job::new(move || {
+        set_output_stream(Some(job_output));
+        set_line_reader(Some(Box::new(move || loop {
+            {
+                let mut input = job_input.lock();
+                if let Some(line) = input.lines.pop_front() {
+                    return line;
+                }
+                if input.closed {
+                    return String::new();
+                }
+                input.wanted = true;
+            }
+            job::suspend();
+        })));
+        dispatch_command(&line);
+    });
+    loop {
+        let finished = job.resume();
+        let text = core::mem::take(&mut *output.lock());
+        if !text.is_empty() && !connection.closed {
+            connection.write_text(&text).await;
+        }
+        if finished {
+            return;
+        }
+        if !core::mem::replace(&mut input.lock().wanted, false) {
+            // Waiting for the network; let everything else have a turn.
+            task::sleep(Duration::from_millis(1)).await;
+            continue;
+        }
+        let line = if connection.closed {
+            None
+        } else {
+            let timeout = Duration::from_millis(INPUT_TIMEOUT_MS);
+            task::timeout(timeout, connection.read_line(true))
+                .await
+                .unwrap_or(None)
+        };
+        let mut input = input.lock();
+        match line {
+            Some(line) => input.lines.push_back(line),
+            None => {
+                // Let the command run to its end, so it releases what it holds.
+                input.closed = true;
+                connection.closed = true;
+            }
+        }
+    }
+}
+
+async fn login(connection: &mut Connection, password: &str) -> bool {
+    for _ in 0..MAX_LOGIN_ATTEMPTS {
+        connection.write_text("Password: ").await;
+        match connection.read_line(false).await {
+            Some(line) if line == password => return true,
+            Some(_) => connection.write_text("Login incorrect.\n").await,
+            None => return false,
+        }
+    }
+    false
+}
+
+async fn session(stream: TcpStream) {
+    let peer = stream.peer_addr();
+    if let Ok(peer) = peer {
+        println!("[INFO] telnetd: session from {} opened.", peer);
+    }
+    let mut connection = Connection::new(stream);
+    let _ = connection
+        .stream
+        .write_all(&[IAC, WILL, OPTION_ECHO, IAC, WILL, OPTION_SUPPRESS_GO_AHEAD])
+        .await;
+
+    let password = PASSWORD.lock().clone();
+    if let Some(password) = password {
+        if !login(&mut connection, &password).await {
+            connection.write_text("Goodbye.\n").await;
+            return;
+        }
+    }
+    connection
+        .write_text("Welcome to KukiOS. Type exit to disconnect.\n")
+        .await;
+    while !connection.closed {
+        connection.write_text(">>>> ").await;
+        let line = match connection.read_line(true).await {
+            Some(line) => line,
+            None => break,
+        };
+        match line.trim() {
+            "" => continue,
+            "exit" | "logout" => break,
+            _ => {}
+        }
+        run_command(&mut connection, &line).await;
+    }
+    let _ = connection.stream.flush().await;
+    if let Ok(peer) = peer {
+        println!("[INFO] telnetd: session from {} closed.", peer);
+    }
+}
+
+pub fn telnetd(args: &[&str]) {
+    let (port, password) = match args {
+        [] | ["status"] => {
+            match SERVICE.port() {
+                Some(port) if PASSWORD.lock().is_some() => {
+                    println!("telnetd: listening on port {} (password protected)", port)
+                }
+                Some(port) => println!("telnetd: listening on port {}", port),
+                None => println!("telnetd: not running"),
+            }
+            return;
+        }
+        ["stop"] => {
+            match SERVICE.stop() {
+                Ok(()) => println!("[OK] telnetd stopped."),
+                Err(err) => println!("telnetd: {}", err),
+            }
+            return;
+        }
+        ["start"] => (Some(DEFAULT_PORT), None),
+        ["start", port] => (port.parse().ok(), None),
+        ["start", "-p", password] => (Some(DEFAULT_PORT), Some(*password)),
+        ["start", port, "-p", password] => (port.parse().ok(), Some(*password)),
+        _ => {
+            println!("Usage: telnetd [status | start [port] [-p password] | stop]");
+            return;
+        }
+    };
+    let port = match port {
+        Some(port) => port,
+        None => {
+            println!("telnetd: invalid port");
+            return;
+        }
+    };
+    if SERVICE.port().is_some() {
+        println!("telnetd: Already running.");
+        return;
+    }
+    *PASSWORD.lock() = password.map(String::from);
+    match SERVICE.start(port, session) {
+        Ok(()) => println!("[OK] telnetd listening on port {}.", port),
+        Err(err) => println!("telnetd: {}", err),
+    }
+}
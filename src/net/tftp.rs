@@ -0,0 +1,297 @@
+use alloc::vec::Vec;
+
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::{
+    interrupts::uptime_millis,
+    net::{dns, UdpSocket},
+    println, vfs,
+};
+
+const TFTP_PORT: u16 = 69;
+const BLOCK_SIZE: usize = 512;
+/// Opcode, block number and a full block.
+const MAX_PACKET_SIZE: usize = 4 + BLOCK_SIZE;
+const ATTEMPT_TIMEOUT_MS: u64 = 2000;
+/// Sends of the same packet before the transfer is given up.
+const ATTEMPTS: usize = 5;
+/// Received files are kept in memory; this bounds them even when the target
+/// takes anything, like a device.
+const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_WRQ: u16 = 2;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const ERROR_DISK_FULL: u16 = 3;
+const ERROR_UNKNOWN_TID: u16 = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Data(u16, &'a [u8]),
+    Ack(u16),
+    Error(u16),
+}
+
+impl Packet<'_> {
+    fn parse(data: &[u8]) -> Option<Packet> {
+        let opcode = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+        let argument = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+        match opcode {
+            OPCODE_DATA if data.len() <= MAX_PACKET_SIZE => {
+                Some(Packet::Data(argument, &data[4..]))
+            }
+            OPCODE_ACK => Some(Packet::Ack(argument)),
+            OPCODE_ERROR => Some(Packet::Error(argument)),
+            _ => None,
+        }
+    }
+}
+
+fn error_message(code: u16) -> &'static str {
+    match code {
+        1 => "File not found.",
+        2 => "Access violation.",
+        3 => "Disk full.",
+        4 => "Illegal TFTP operation.",
+        5 => "Unknown transfer ID.",
+        6 => "File already exists.",
+        7 => "No such user.",
+        _ => "Server reported an error.",
+    }
+}
+
+/// Writes a read or write request for `filename` in octet mode.
+fn encode_request(buffer: &mut [u8], opcode: u16, filename: &str) -> Result<usize, &'static str> {
+    let name = filename.as_bytes();
+    let len = 2 + name.len() + 1 + b"octet".len() + 1;
+    if name.is_empty() || name.contains(&0) || len > buffer.len() {
+        return Err("Invalid file name.");
+    }
+    buffer[..2].copy_from_slice(&opcode.to_be_bytes());
+    buffer[2..2 + name.len()].copy_from_slice(name);
+    buffer[2 + name.len()] = 0;
+    buffer[3 + name.len()..len - 1].copy_from_slice(b"octet");
+    buffer[len - 1] = 0;
+    Ok(len)
+}
+
+fn encode(opcode: u16, argument: u16, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4 + data.len());
+    packet.extend_from_slice(&opcode.to_be_bytes());
+    packet.extend_from_slice(&argument.to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// One transfer. The server answers from a fresh port, its transfer ID, and
+/// the rest of the transfer talks to that port only.
+struct Transfer {
+    socket: UdpSocket,
+    server: IpAddress,
+    peer: Option<IpEndpoint>,
+}
+
+impl Transfer {
+    fn open(host: &str) -> Result<Transfer, &'static str> {
+        let server = IpAddress::Ipv4(dns::resolve_ipv4(host)?);
+        Ok(Transfer {
+            socket: UdpSocket::bind(0)?,
+            server,
+            peer: None,
+        })
+    }
+
+    fn send(&self, packet: &[u8]) -> Result<(), &'static str> {
+        let destination = self
+            .peer
+            .unwrap_or_else(|| IpEndpoint::new(self.server, TFTP_PORT));
+        self.socket.send_to_blocking(packet, destination)
+    }
+
+    /// Sends `packet` until the server answers with something `wanted` accepts,
+    /// retransmitting on every timeout, and returns the answer's payload. Other
+    /// packets, like duplicates of a block we already have, are dropped.
+    fn exchange(
+        &mut self,
+        packet: &[u8],
+        wanted: impl Fn(&Packet) -> bool,
+    ) -> Result<Vec<u8>, &'static str> {
+        // One byte spare, so oversized packets show as such instead of truncated.
+        let mut buffer = [0u8; MAX_PACKET_SIZE + 1];
+        for _ in 0..ATTEMPTS {
+            self.send(packet)?;
+            let deadline = uptime_millis() + ATTEMPT_TIMEOUT_MS;
+            loop {
+                let remaining = deadline.saturating_sub(uptime_millis());
+                let (count, from) = match self.socket.recv_from_blocking(&mut buffer, remaining)? {
+                    Some(received) => received,
+                    None => break,
+                };
+                if from.addr != self.server {
+                    continue;
+                }
+                match self.peer {
+                    Some(peer) if peer != from => {
+                        let error = encode(OPCODE_ERROR, ERROR_UNKNOWN_TID, b"\0");
+                        let _ = self.socket.send_to_blocking(&error, from);
+                        continue;
+                    }
+                    _ => {}
+                }
+                let packet = match Packet::parse(&buffer[..count]) {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if let Packet::Error(code) = packet {
+                    return Err(error_message(code));
+                }
+                if !wanted(&packet) {
+                    continue;
+                }
+                self.peer = Some(from);
+                return Ok(match packet {
+                    Packet::Data(_, data) => data.to_vec(),
+                    _ => Vec::new(),
+                });
+            }
+        }
+        Err("Transfer timed out.")
+    }
+
+    /// Receives `remote`, giving up with a "disk full" error to the server
+    /// once it grows past `limit` bytes.
+    fn get(&mut self, remote: &str, limit: usize) -> Result<Vec<u8>, &'static str> {
+        let mut request = [0u8; MAX_PACKET_SIZE];
+        let len = encode_request(&mut request, OPCODE_RRQ, remote)?;
+        let mut packet = request[..len].to_vec();
+        let mut file = Vec::new();
+        let mut block: u16 = 1;
+        loop {
+            let data = self.exchange(
+                &packet,
+                |reply| matches!(reply, Packet::Data(number, _) if *number == block),
+            )?;
+            file.extend_from_slice(&data);
+            if file.len() > limit {
+                let _ = self.send(&encode(OPCODE_ERROR, ERROR_DISK_FULL, b"File too large.\0"));
+                return Err("File too large.");
+            }
+            // Nothing answers the final ACK; if it gets lost the server retries
+            // a few times against a closed port and gives up.
+            let ack = encode(OPCODE_ACK, block, &[]);
+            if data.len() < BLOCK_SIZE {
+                self.send(&ack)?;
+                return Ok(file);
+            }
+            packet = ack;
+            block = block.wrapping_add(1);
+        }
+    }
+
+    fn put(&mut self, remote: &str, file: &[u8]) -> Result<(), &'static str> {
+        let mut request = [0u8; MAX_PACKET_SIZE];
+        let len = encode_request(&mut request, OPCODE_WRQ, remote)?;
+        let mut packet = request[..len].to_vec();
+        let mut block: u16 = 0;
+        // A file that fills its last block exactly ends with an empty one.
+        let mut blocks = file
+            .chunks(BLOCK_SIZE)
+            .chain(core::iter::once(&[][..]).filter(|_| file.len() % BLOCK_SIZE == 0));
+        loop {
+            self.exchange(
+                &packet,
+                |reply| matches!(reply, Packet::Ack(number) if *number == block),
+            )?;
+            let data = match blocks.next() {
+                Some(data) => data,
+                None => return Ok(()),
+            };
+            block = block.wrapping_add(1);
+            packet = encode(OPCODE_DATA, block, data);
+        }
+    }
+}
+
+pub fn tftp(args: &[&str]) {
+    let started = uptime_millis();
+    match args {
+        ["get", host, remote] | ["get", host, remote, _] => {
+            let local = args.get(3).copied().unwrap_or(*remote);
+            // Known before the first packet, so a transfer that can't be saved
+            // never starts.
+            let limit = match vfs::max_file_size(local) {
+                Ok(limit) => usize::min(limit, MAX_FILE_SIZE),
+                Err(err) => {
+                    println!("tftp: {}: {}", local, err);
+                    return;
+                }
+            };
+            let received =
+                Transfer::open(host).and_then(|mut transfer| transfer.get(remote, limit));
+            let file = match received {
+                Ok(file) => file,
+                Err(err) => {
+                    println!("tftp: {}: {}", remote, err);
+                    return;
+                }
+            };
+            match vfs::write(local, &file) {
+                Ok(count) => println!(
+                    "Received {} bytes in {} ms, saved to {}.",
+                    count,
+                    uptime_millis() - started,
+                    local
+                ),
+                Err(err) => println!("tftp: {}: {}", local, err),
+            }
+        }
+        ["put", host, local] | ["put", host, local, _] => {
+            let remote = args.get(3).copied().unwrap_or(*local);
+            let file = match vfs::read(local, None) {
+                Ok(file) => file,
+                Err(err) => {
+                    println!("tftp: {}: {}", local, err);
+                    return;
+                }
+            };
+            match Transfer::open(host).and_then(|mut transfer| transfer.put(remote, &file)) {
+                Ok(()) => println!(
+                    "Sent {} bytes in {} ms to {}:{}.",
+                    file.len(),
+                    uptime_millis() - started,
+                    host,
+                    remote
+                ),
+                Err(err) => println!("tftp: {}: {}", remote, err),
+            }
+        }
+        _ => {
+            println!("Usage: tftp get <host> <remote> [local]");
+            println!("       tftp put <host> <local> [remote]");
+        }
+    }
+}
+
+#[test_case]
+fn test_encode_request() {
+    let mut buffer = [0u8; 32];
+    let len = encode_request(&mut buffer, OPCODE_RRQ, "kernel.bin").unwrap();
+    assert_eq!(&buffer[..len], b"\x00\x01kernel.bin\x00octet\x00");
+    assert!(encode_request(&mut buffer, OPCODE_WRQ, "").is_err());
+}
+
+#[test_case]
+fn test_parse_packet() {
+    assert_eq!(
+        Packet::parse(b"\x00\x03\x00\x07abc"),
+        Some(Packet::Data(7, b"abc"))
+    );
+    assert_eq!(Packet::parse(b"\x00\x04\x01\x00"), Some(Packet::Ack(256)));
+    assert_eq!(
+        Packet::parse(b"\x00\x05\x00\x01nope\x00"),
+        Some(Packet::Error(1))
+    );
+    assert_eq!(Packet::parse(b"\x00\x03"), None);
+}
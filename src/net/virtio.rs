@@ -0,0 +1,590 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{fence, AtomicBool, AtomicU16, AtomicU64, Ordering},
+};
+
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{
+    interrupts::register_irq_handler,
+    memory::{alloc_dma, map_mmio, DmaRegion},
+    net::{self, register_nic, Nic, MAX_FRAME_SIZE},
+    pci::{self, Bar, PciDevice, PciDriver},
+    println,
+};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// Transitional devices speak the legacy I/O port interface, modern ones only MMIO.
+const VIRTIO_NET_TRANSITIONAL_ID: u16 = 0x1000;
+const VIRTIO_NET_MODERN_ID: u16 = 0x1041;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const ISR_QUEUE: u8 = 1 << 0;
+const ISR_CONFIG: u8 = 1 << 1;
+
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+// Legacy I/O port register layout, relative to BAR0.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// Device config follows the common registers as long as MSI-X stays off.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Modern common configuration structure offsets.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+const PCI_CAP_VENDOR_SPECIFIC: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// Upper bound for queues we size ourselves; legacy devices dictate theirs.
+const MAX_QUEUE_SIZE: u16 = 128;
+const BUFFER_SIZE: usize = 2048;
+
+/// Where the interrupt handler finds the ISR status register.
+static ISR_PORT: AtomicU16 = AtomicU16::new(0);
+static ISR_MMIO: AtomicU64 = AtomicU64::new(0);
+static CONFIG_CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub fn register_driver() {
+    for &device_id in &[VIRTIO_NET_TRANSITIONAL_ID, VIRTIO_NET_MODERN_ID] {
+        pci::register_driver(PciDriver {
+            name: "virtio-net",
+            vendor_id: VIRTIO_VENDOR_ID,
+            device_id: Some(device_id),
+            probe,
+        });
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), &'static str> {
+    device.enable();
+    let transport = if device.device_id == VIRTIO_NET_TRANSITIONAL_ID {
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Transport::Legacy(port),
+            _ => return Err("BAR0 is not an I/O BAR."),
+        }
+    } else {
+        Transport::modern(device)?
+    };
+    let net = VirtioNet::new(transport)?;
+    register_irq_handler(device.interrupt_line, handle_interrupt)?;
+    let mac = net.mac;
+    println!(
+        "[OK] virtio-net at {} ({}): MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, IRQ {}",
+        device.address,
+        net.transport.name(),
+        mac[0],
+        mac[1],
+        mac[2],
+        mac[3],
+        mac[4],
+        mac[5],
+        device.interrupt_line
+    );
+    register_nic(Box::new(net));
+    Ok(())
+}
+
+/// Runs in interrupt context. Reading the ISR acknowledges the interrupt, and
+/// a zero means it came from some other device on the shared line.
+fn handle_interrupt() {
+    let port = ISR_PORT.load(Ordering::Relaxed);
+    let mmio = ISR_MMIO.load(Ordering::Relaxed);
+    let isr = if port != 0 {
+        unsafe { Port::<u8>::new(port).read() }
+    } else if mmio != 0 {
+        unsafe { read_volatile(mmio as *const u8) }
+    } else {
+        return;
+    };
+    if isr & ISR_CONFIG != 0 {
+        CONFIG_CHANGED.store(true, Ordering::Relaxed);
+    }
+    if isr & (ISR_QUEUE | ISR_CONFIG) != 0 {
+        net::wake();
+    }
+}
+
+/// How registers are reached: I/O ports on legacy devices, mapped BARs on modern ones.
+enum Transport {
+    Legacy(u16),
+    Modern {
+        common: u64,
+        notify: u64,
+        notify_multiplier: u32,
+        isr: u64,
+        device: u64,
+    },
+}
+
+impl Transport {
+    fn modern(device: &PciDevice) -> Result<Transport, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_cfg = None;
+        let mut notify_multiplier = 0;
+
+        let config = device.address;
+        let mut offset = device
+            .find_capability(PCI_CAP_VENDOR_SPECIFIC)
+            .ok_or("No virtio capabilities.")?;
+        while offset != 0 {
+            if config.read_u8(offset) == PCI_CAP_VENDOR_SPECIFIC {
+                let cfg_type = config.read_u8(offset + 3);
+                let bar = usize::from(config.read_u8(offset + 4));
+                let bar_offset = config.read_u32(offset + 8);
+                let length = config.read_u32(offset + 12);
+                let base = match device.bars.get(bar) {
+                    Some(Some(Bar::Memory { address, .. })) => *address,
+                    _ => 0,
+                };
+                if base != 0 {
+                    let region = (base + u64::from(bar_offset), length as usize);
+                    match cfg_type {
+                        VIRTIO_PCI_CAP_COMMON_CFG if common.is_none() => common = Some(region),
+                        VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                            notify_multiplier = config.read_u32(offset + 16);
+                            notify = Some(region);
+                        }
+                        VIRTIO_PCI_CAP_ISR_CFG if isr.is_none() => isr = Some(region),
+                        VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
+                            device_cfg = Some(region)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            offset = config.read_u8(offset + 1) & 0xFC;
+        }
+
+        let map = |region: Option<(u64, usize)>| -> Result<u64, &'static str> {
+            let (phys, length) = region.ok_or("Missing virtio capability.")?;
+            Ok(map_mmio(PhysAddr::new(phys), length)?.as_u64())
+        };
+        Ok(Transport::Modern {
+            common: map(common)?,
+            notify: map(notify)?,
+            notify_multiplier,
+            isr: map(isr)?,
+            device: map(device_cfg)?,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Transport::Legacy(_) => "legacy",
+            Transport::Modern { .. } => "modern",
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                u64::from(Port::<u32>::new(base + LEGACY_DEVICE_FEATURES).read())
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile((common + COMMON_DEVICE_FEATURE_SELECT) as *mut u32, 0);
+                let low = read_volatile((common + COMMON_DEVICE_FEATURE) as *const u32);
+                write_volatile((common + COMMON_DEVICE_FEATURE_SELECT) as *mut u32, 1);
+                let high = read_volatile((common + COMMON_DEVICE_FEATURE) as *const u32);
+                u64::from(low) | u64::from(high) << 32
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u32>::new(base + LEGACY_DRIVER_FEATURES).write(features as u32)
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile((common + COMMON_DRIVER_FEATURE_SELECT) as *mut u32, 0);
+                write_volatile((common + COMMON_DRIVER_FEATURE) as *mut u32, features as u32);
+                write_volatile((common + COMMON_DRIVER_FEATURE_SELECT) as *mut u32, 1);
+                write_volatile(
+                    (common + COMMON_DRIVER_FEATURE) as *mut u32,
+                    (features >> 32) as u32,
+                );
+            },
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u8>::new(base + LEGACY_DEVICE_STATUS).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                read_volatile((common + COMMON_DEVICE_STATUS) as *const u8)
+            },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u8>::new(base + LEGACY_DEVICE_STATUS).write(status)
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile((common + COMMON_DEVICE_STATUS) as *mut u8, status)
+            },
+        }
+    }
+
+    fn config_u8(&self, offset: u16) -> u8 {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u8>::new(base + LEGACY_DEVICE_CONFIG + offset).read()
+            },
+            Transport::Modern { device, .. } => unsafe {
+                read_volatile((device + u64::from(offset)) as *const u8)
+            },
+        }
+    }
+
+    fn config_u16(&self, offset: u16) -> u16 {
+        u16::from(self.config_u8(offset)) | u16::from(self.config_u8(offset + 1)) << 8
+    }
+
+    /// Selects queue `index` and returns the size we will run it with.
+    fn queue_size(&self, index: u16) -> u16 {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_SELECT).write(index);
+                Port::<u16>::new(base + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write_volatile((common + COMMON_QUEUE_SELECT) as *mut u16, index);
+                let max = read_volatile((common + COMMON_QUEUE_SIZE) as *const u16);
+                u16::min(max, MAX_QUEUE_SIZE)
+            },
+        }
+    }
+
+    /// Tells the device where queue `index` lives and returns its notify offset.
+    fn activate_queue(&self, index: u16, queue: &Virtqueue) -> u64 {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_SELECT).write(index);
+                Port::<u32>::new(base + LEGACY_QUEUE_ADDRESS)
+                    .write((queue.region.phys.as_u64() / 4096) as u32);
+                0
+            },
+            Transport::Modern {
+                common,
+                notify_multiplier,
+                ..
+            } => unsafe {
+                let phys = queue.region.phys.as_u64();
+                write_volatile((common + COMMON_QUEUE_SELECT) as *mut u16, index);
+                write_volatile((common + COMMON_QUEUE_SIZE) as *mut u16, queue.size);
+                write_volatile((common + COMMON_QUEUE_DESC) as *mut u64, phys);
+                write_volatile(
+                    (common + COMMON_QUEUE_DRIVER) as *mut u64,
+                    phys + queue.avail_offset as u64,
+                );
+                write_volatile(
+                    (common + COMMON_QUEUE_DEVICE) as *mut u64,
+                    phys + queue.used_offset as u64,
+                );
+                let notify_off = read_volatile((common + COMMON_QUEUE_NOTIFY_OFF) as *const u16);
+                write_volatile((common + COMMON_QUEUE_ENABLE) as *mut u16, 1);
+                u64::from(notify_off) * u64::from(notify_multiplier)
+            },
+        }
+    }
+
+    fn notify(&self, index: u16, notify_offset: u64) {
+        match *self {
+            Transport::Legacy(base) => unsafe {
+                Port::<u16>::new(base + LEGACY_QUEUE_NOTIFY).write(index)
+            },
+            Transport::Modern { notify, .. } => unsafe {
+                write_volatile((notify + notify_offset) as *mut u16, index)
+            },
+        }
+    }
+
+    fn publish_isr(&self) {
+        match *self {
+            Transport::Legacy(base) => ISR_PORT.store(base + LEGACY_ISR_STATUS, Ordering::Relaxed),
+            Transport::Modern { isr, .. } => ISR_MMIO.store(isr, Ordering::Relaxed),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue with one fixed-size buffer per descriptor.
+struct Virtqueue {
+    index: u16,
+    size: u16,
+    region: DmaRegion,
+    avail_offset: usize,
+    used_offset: usize,
+    buffers: DmaRegion,
+    free: Vec<u16>,
+    last_used: u16,
+    notify_offset: u64,
+}
+
+impl Virtqueue {
+    /// Lays the queue out the way legacy devices expect it: descriptors, then the
+    /// available ring, then the used ring on the next page. Modern devices take
+    /// the same layout.
+    fn new(index: u16, size: u16) -> Result<Virtqueue, &'static str> {
+        if size == 0 {
+            return Err("Virtqueue not available.");
+        }
+        let count = usize::from(size);
+        let avail_offset = count * core::mem::size_of::<VirtqDesc>();
+        let used_offset = align_up(avail_offset + 6 + 2 * count, 4096);
+        let total = used_offset + align_up(6 + 8 * count, 4096);
+        let out_of_memory = "Not enough memory for virtqueues.";
+        Ok(Virtqueue {
+            index,
+            size,
+            region: alloc_dma(total).ok_or(out_of_memory)?,
+            avail_offset,
+            used_offset,
+            buffers: alloc_dma(count * BUFFER_SIZE).ok_or(out_of_memory)?,
+            free: (0..size).rev().collect(),
+            last_used: 0,
+            notify_offset: 0,
+        })
+    }
+
+    fn buffer(&self, id: u16) -> *mut u8 {
+        (self.buffers.virt.as_u64() + u64::from(id) * BUFFER_SIZE as u64) as *mut u8
+    }
+
+    fn field<T>(&self, offset: usize) -> *mut T {
+        (self.region.virt.as_u64() + offset as u64) as *mut T
+    }
+
+    /// Hands descriptor `id`, covering `len` bytes of its buffer, to the device.
+    fn push(&mut self, id: u16, len: usize, flags: u16) {
+        let desc = VirtqDesc {
+            addr: self.buffers.phys.as_u64() + u64::from(id) * BUFFER_SIZE as u64,
+            len: len as u32,
+            flags,
+            next: 0,
+        };
+        unsafe {
+            write_volatile(self.field::<VirtqDesc>(usize::from(id) * 16), desc);
+            let avail_idx = read_volatile(self.field::<u16>(self.avail_offset + 2));
+            let slot = usize::from(avail_idx % self.size);
+            write_volatile(self.field::<u16>(self.avail_offset + 4 + 2 * slot), id);
+            // The ring entry has to be visible before the index that publishes it.
+            fence(Ordering::SeqCst);
+            write_volatile(
+                self.field::<u16>(self.avail_offset + 2),
+                avail_idx.wrapping_add(1),
+            );
+        }
+    }
+
+    /// Takes the next descriptor the device is done with, with the bytes it wrote.
+    fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used_idx = unsafe { read_volatile(self.field::<u16>(self.used_offset + 2)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used % self.size);
+        let (id, len) = unsafe {
+            (
+                read_volatile(self.field::<u32>(self.used_offset + 4 + 8 * slot)),
+                read_volatile(self.field::<u32>(self.used_offset + 8 + 8 * slot)),
+            )
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, len as usize))
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+pub struct VirtioNet {
+    transport: Transport,
+    mac: [u8; 6],
+    has_status: bool,
+    link_up: bool,
+    /// `virtio_net_hdr` is 10 bytes on legacy devices and 12 once VERSION_1 is negotiated.
+    header_len: usize,
+    rx: Virtqueue,
+    tx: Virtqueue,
+}
+
+// The queues are only touched while the driver is locked.
+unsafe impl Send for VirtioNet {}
+
+impl VirtioNet {
+    fn new(transport: Transport) -> Result<VirtioNet, &'static str> {
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = transport.device_features();
+        let mut wanted = VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS;
+        let modern = matches!(transport, Transport::Modern { .. });
+        if modern {
+            wanted |= VIRTIO_F_VERSION_1;
+        }
+        let features = offered & wanted;
+        transport.set_driver_features(features);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        if modern {
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.set_status(status | STATUS_FAILED);
+                return Err("Device rejected our feature set.");
+            }
+        }
+
+        let mut rx = Virtqueue::new(RECEIVE_QUEUE, transport.queue_size(RECEIVE_QUEUE))?;
+        rx.notify_offset = transport.activate_queue(RECEIVE_QUEUE, &rx);
+        let mut tx = Virtqueue::new(TRANSMIT_QUEUE, transport.queue_size(TRANSMIT_QUEUE))?;
+        tx.notify_offset = transport.activate_queue(TRANSMIT_QUEUE, &tx);
+
+        let mut mac = [0u8; 6];
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.config_u8(i as u16);
+            }
+        } else {
+            // Locally administered address when the device doesn't offer one.
+            mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        }
+
+        let mut net = VirtioNet {
+            has_status: features & VIRTIO_NET_F_STATUS != 0,
+            link_up: true,
+            header_len: if features & VIRTIO_F_VERSION_1 != 0 { 12 } else { 10 },
+            transport,
+            mac,
+            rx,
+            tx,
+        };
+        net.refresh_link();
+        while let Some(id) = net.rx.free.pop() {
+            net.rx.push(id, BUFFER_SIZE, VIRTQ_DESC_F_WRITE);
+        }
+        net.transport.publish_isr();
+        net.transport.set_status(status | STATUS_DRIVER_OK);
+        net.transport.notify(RECEIVE_QUEUE, net.rx.notify_offset);
+        Ok(net)
+    }
+
+    fn refresh_link(&mut self) {
+        if self.has_status {
+            // The status word sits right after the MAC in the device config.
+            self.link_up = self.transport.config_u16(6) & VIRTIO_NET_S_LINK_UP != 0;
+        }
+    }
+
+    /// Returns transmit buffers the device has finished sending.
+    fn reclaim_tx(&mut self) {
+        while let Some((id, _)) = self.tx.pop_used() {
+            self.tx.free.push(id);
+        }
+    }
+}
+
+impl Nic for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+    fn link_up(&self) -> bool {
+        self.link_up
+    }
+    fn send(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err("Frame too large.");
+        }
+        self.reclaim_tx();
+        let id = self.tx.free.pop().ok_or("Transmit queue full.")?;
+        let buffer = self.tx.buffer(id);
+        unsafe {
+            // An all-zero header means no checksum offload and no segmentation.
+            core::ptr::write_bytes(buffer, 0, self.header_len);
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                buffer.add(self.header_len),
+                frame.len(),
+            );
+        }
+        self.tx.push(id, self.header_len + frame.len(), 0);
+        self.transport.notify(self.tx.index, self.tx.notify_offset);
+        Ok(())
+    }
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        if CONFIG_CHANGED.swap(false, Ordering::Relaxed) {
+            self.refresh_link();
+        }
+        self.reclaim_tx();
+        let (id, len) = self.rx.pop_used()?;
+        let frame = if len > self.header_len {
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    self.rx.buffer(id).add(self.header_len),
+                    usize::min(len, BUFFER_SIZE) - self.header_len,
+                )
+            };
+            Some(data.to_vec())
+        } else {
+            None
+        };
+        // Refill right away so the device never runs dry.
+        self.rx.push(id, BUFFER_SIZE, VIRTQ_DESC_F_WRITE);
+        self.transport.notify(self.rx.index, self.rx.notify_offset);
+        frame.or_else(|| self.receive())
+    }
+}
@@ -0,0 +1,374 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::println;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let address = 0x8000_0000
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC);
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address);
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let address = 0x8000_0000
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC);
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(address);
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | u32::from(value) << shift);
+    }
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; 6],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let id = address.read_u32(0x00);
+        let vendor_id = id as u16;
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class_reg = address.read_u32(0x08);
+        let header_type = address.read_u8(0x0E) & 0x7F;
+        let interrupt = address.read_u32(0x3C);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class_reg >> 24) as u8,
+            subclass: (class_reg >> 16) as u8,
+            prog_if: (class_reg >> 8) as u8,
+            revision: class_reg as u8,
+            header_type,
+            bars: [None; 6],
+            interrupt_line: interrupt as u8,
+            interrupt_pin: (interrupt >> 8) as u8,
+        };
+        if header_type == 0 {
+            device.read_bars();
+        }
+        Some(device)
+    }
+
+    /// Decodes the BARs, sizing each one by writing all ones and reading back the mask.
+    fn read_bars(&mut self) {
+        let command = self.address.read_u16(0x04);
+        // Decoding has to be off while the BARs hold the sizing pattern.
+        self.address
+            .write_u16(0x04, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let mut index = 0;
+        while index < 6 {
+            let offset = 0x10 + index as u8 * 4;
+            let value = self.address.read_u32(offset);
+            self.address.write_u32(offset, 0xFFFF_FFFF);
+            let mask = self.address.read_u32(offset);
+            self.address.write_u32(offset, value);
+
+            if value & 1 == 1 {
+                let size = (!(mask & 0xFFFF_FFFC)).wrapping_add(1) & 0xFFFF;
+                if value & 0xFFFF_FFFC != 0 {
+                    self.bars[index] = Some(Bar::Io {
+                        port: (value & 0xFFFC) as u16,
+                        size,
+                    });
+                }
+                index += 1;
+                continue;
+            }
+
+            let is_64bit = (value >> 1) & 0b11 == 0b10;
+            let prefetchable = value & 0x8 != 0;
+            let mut address = u64::from(value & 0xFFFF_FFF0);
+            let mut size_mask = u64::from(mask & 0xFFFF_FFF0) | 0xFFFF_FFFF_0000_0000;
+            if is_64bit && index < 5 {
+                let high_offset = offset + 4;
+                let high = self.address.read_u32(high_offset);
+                self.address.write_u32(high_offset, 0xFFFF_FFFF);
+                let high_mask = self.address.read_u32(high_offset);
+                self.address.write_u32(high_offset, high);
+                address |= u64::from(high) << 32;
+                size_mask = (size_mask & 0xFFFF_FFFF) | u64::from(high_mask) << 32;
+            }
+            let size = (!size_mask).wrapping_add(1);
+            if address != 0 || mask != 0 {
+                self.bars[index] = Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                });
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+        self.address.write_u16(0x04, command);
+    }
+
+    pub fn command(&self) -> u16 {
+        self.address.read_u16(0x04)
+    }
+
+    /// Turns on I/O and memory decoding, DMA and legacy INTx delivery.
+    pub fn enable(&self) {
+        let command = self.command();
+        self.address.write_u16(
+            0x04,
+            (command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER)
+                & !COMMAND_INTERRUPT_DISABLE,
+        );
+    }
+
+    /// Walks the capability list and returns the offset of capability `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        let status = self.address.read_u16(0x06);
+        if status & (1 << 4) == 0 {
+            return None;
+        }
+        let mut offset = self.address.read_u8(0x34) & 0xFC;
+        while offset != 0 {
+            if self.address.read_u8(offset) == id {
+                return Some(offset);
+            }
+            offset = self.address.read_u8(offset + 1) & 0xFC;
+        }
+        None
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, 0x80) => "Bridge",
+        (0x06, _) => "Bridge device",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0xFF, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+/// A driver that wants PCI devices matching `vendor_id`/`device_id`, or every
+/// device of the vendor when `device_id` is `None`.
+pub struct PciDriver {
+    pub name: &'static str,
+    pub vendor_id: u16,
+    pub device_id: Option<u16>,
+    pub probe: fn(&PciDevice) -> Result<(), &'static str>,
+}
+
+impl PciDriver {
+    fn matches(&self, device: &PciDevice) -> bool {
+        device.vendor_id == self.vendor_id
+            && self.device_id.map_or(true, |id| id == device.device_id)
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+    static ref DRIVERS: Mutex<Vec<PciDriver>> = Mutex::new(Vec::new());
+}
+
+/// Scans every bus, device and function and remembers what answered.
+pub fn init() {
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let probed = match PciDevice::probe(first) {
+                Some(probed) => probed,
+                None => continue,
+            };
+            let multifunction = first.read_u8(0x0E) & 0x80 != 0;
+            devices.push(probed);
+            if multifunction {
+                for function in 1..8u8 {
+                    let address = PciAddress {
+                        bus,
+                        device,
+                        function,
+                    };
+                    if let Some(probed) = PciDevice::probe(address) {
+                        devices.push(probed);
+                    }
+                }
+            }
+        }
+    }
+    println!("[OK] PCI: found {} functions.", devices.len());
+    *DEVICES.lock() = devices;
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+        .cloned()
+}
+
+pub fn register_driver(driver: PciDriver) {
+    DRIVERS.lock().push(driver);
+}
+
+/// Hands every device to the first registered driver that wants it.
+pub fn probe_drivers() {
+    let devices = devices();
+    let drivers = DRIVERS.lock();
+    for device in &devices {
+        if let Some(driver) = drivers.iter().find(|driver| driver.matches(device)) {
+            match (driver.probe)(device) {
+                Ok(()) => println!("[OK] {} bound to {}", driver.name, device.address),
+                Err(err) => println!("[FAIL] {} on {}: {}", driver.name, device.address, err),
+            }
+        }
+    }
+}
+
+pub fn lspci(args: &[&str]) {
+    let verbose = args.contains(&"-v");
+    for device in devices() {
+        println!(
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            device.address,
+            device.class_name(),
+            device.class,
+            device.subclass,
+            device.vendor_id,
+            device.device_id,
+            device.revision
+        );
+        if !verbose {
+            continue;
+        }
+        if device.interrupt_pin != 0 {
+            println!(
+                "    Interrupt: pin {} routed to IRQ {}",
+                (b'A' + device.interrupt_pin - 1) as char,
+                device.interrupt_line
+            );
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Io { port, size }) => {
+                    println!("    BAR{}: I/O ports at {:#06x} [size={}]", index, port, size)
+                }
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                }) => println!(
+                    "    BAR{}: Memory at {:#x} ({}-bit, {}) [size={}K]",
+                    index,
+                    address,
+                    if *is_64bit { 64 } else { 32 },
+                    if *prefetchable {
+                        "prefetchable"
+                    } else {
+                        "non-prefetchable"
+                    },
+                    size / 1024
+                ),
+                None => {}
+            }
+        }
+    }
+}
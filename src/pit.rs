@@ -0,0 +1,62 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Input clock of the 8254 programmable interval timer.
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+/// Timer interrupts per second once [`init`] has run.
+pub const TIMER_HZ: u64 = 1000;
+/// Reload value for channel 0; 1193 gives 1000.15 Hz.
+pub const DIVISOR: u64 = (BASE_FREQUENCY + TIMER_HZ / 2) / TIMER_HZ;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 0, counter latch.
+const CHANNEL_0_LATCH: u8 = 0b0000_0000;
+
+/// Programs channel 0 to interrupt [`TIMER_HZ`] times a second.
+pub fn init() {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(CHANNEL_0_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        data.write((DIVISOR & 0xFF) as u8);
+        data.write((DIVISOR >> 8) as u8);
+    });
+}
+
+/// Channel 0's current count, running down from [`DIVISOR`] once per tick.
+fn read_counter() -> u64 {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(CHANNEL_0_LATCH);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        let low = u64::from(data.read());
+        let high = u64::from(data.read());
+        high << 8 | low
+    })
+}
+
+/// Busy-waits `ms` milliseconds by watching the counter itself, for callers
+/// that run with interrupts off and so never see the tick count move.
+pub fn wait_polled(ms: u64) {
+    wait_clocks(ms * BASE_FREQUENCY / 1000);
+}
+
+/// Busy-waits for `clocks` cycles of the [`BASE_FREQUENCY`] input clock.
+pub fn wait_clocks(target: u64) {
+    let mut elapsed = 0;
+    let mut last = read_counter();
+    while elapsed < target {
+        let now = read_counter();
+        // The counter reloads at zero, so a larger value means it wrapped.
+        elapsed += (last + DIVISOR - now) % DIVISOR;
+        last = now;
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_sleep_waits() {
+    let start = crate::interrupts::uptime_millis();
+    crate::sleep(20);
+    assert!(crate::interrupts::uptime_millis() - start >= 20);
+}
@@ -0,0 +1,72 @@
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use crate::{
+    allocator::{heap_used, HEAP_SIZE},
+    interrupts::{interrupt_counts, uptime_millis, vector_name},
+    memory::frame_stats,
+    task::task_states,
+};
+
+/// Files under `/proc`, each generated fresh on every read.
+static FILES: [(&str, fn() -> String); 6] = [
+    ("meminfo", meminfo),
+    ("tasks", tasks),
+    ("interrupts", interrupts),
+    ("uptime", uptime),
+    ("version", version),
+    ("mounts", mounts),
+];
+
+pub fn lookup(name: &str) -> Option<fn() -> String> {
+    FILES
+        .iter()
+        .find(|(file_name, _)| *file_name == name)
+        .map(|(_, generate)| *generate)
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    FILES.iter().map(|(name, _)| *name)
+}
+
+fn meminfo() -> String {
+    let used = heap_used();
+    let (frames_allocated, frames_usable) = frame_stats();
+    format!(
+        "HeapTotal:      {:>8} kB\nHeapUsed:       {:>8} kB\nHeapFree:       {:>8} kB\nFramesUsable:   {:>8}\nFramesAllocated:{:>8}\n",
+        HEAP_SIZE / 1024,
+        used / 1024,
+        (HEAP_SIZE - used) / 1024,
+        frames_usable,
+        frames_allocated,
+    )
+}
+
+fn tasks() -> String {
+    let mut out = String::from("ID     STATE\n");
+    for (task_id, state) in task_states() {
+        let _ = writeln!(out, "{:<6} {:?}", task_id, state);
+    }
+    out
+}
+
+fn interrupts() -> String {
+    let mut out = String::from("VECTOR COUNT      NAME\n");
+    for (vector, count) in interrupt_counts() {
+        let _ = writeln!(out, "{:<6} {:<10} {}", vector, count, vector_name(vector));
+    }
+    out
+}
+
+fn uptime() -> String {
+    let millis = uptime_millis();
+    format!("{}.{:03}\n", millis / 1000, millis % 1000)
+}
+
+fn version() -> String {
+    format!("KukiOS version {} (x86_64)\n", env!("CARGO_PKG_VERSION"))
+}
+
+fn mounts() -> String {
+    String::from("memfs / memfs rw\ndevfs /dev devfs rw\nprocfs /proc procfs ro\n")
+}
@@ -1,13 +1,266 @@
-use core::str::FromStr;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 
-use smoltcp::wire::IpAddress;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+use crate::{
+    net::{dns, TcpStream},
+    println, vfs,
+};
+
+const DEFAULT_PORT: u16 = 80;
+const MAX_REDIRECTS: usize = 5;
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+/// Give up when the server goes quiet for this long mid-response.
+const IDLE_TIMEOUT_MS: u64 = 10_000;
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starting with `/`.
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, &'static str> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else if url.starts_with("https://") {
+            return Err("HTTPS is not supported.");
+        } else if url.contains("://") {
+            return Err("Only http:// URLs are supported.");
+        } else {
+            url
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "Invalid port in URL.")?),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err("URL has no host.");
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Resolves a `Location` header, which may be absolute or relative to this URL.
+    fn join(&self, location: &str) -> Result<Url, &'static str> {
+        if location.contains("://") {
+            return Url::parse(location);
+        }
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let base = &self.path[..self.path.rfind('/').map_or(0, |index| index + 1)];
+            format!("{}{}", base, location)
+        };
+        Ok(Url {
+            host: self.host.clone(),
+            port: self.port,
+            path,
+        })
+    }
+
+    fn host_header(&self) -> String {
+        if self.port == DEFAULT_PORT {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// First header called `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
 
 pub struct Request;
 
 impl Request {
-    pub fn get(url: &str) -> Result<&str, &'static str> {
-        // let address = IpAddress::from_str()
+    /// Fetches `url` over HTTP/1.1, following up to five redirects.
+    pub fn get(url: &str) -> Result<Response, &'static str> {
+        let mut url = Url::parse(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let response = Self::send(&url)?;
+            match response.status {
+                301 | 302 | 303 | 307 | 308 => {
+                    let location = response
+                        .header("Location")
+                        .ok_or("Redirect without a Location header.")?;
+                    url = url.join(location)?;
+                }
+                _ => return Ok(response),
+            }
+        }
+        Err("Too many redirects.")
+    }
+
+    fn send(url: &Url) -> Result<Response, &'static str> {
+        let address = dns::resolve_ipv4(&url.host)?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: KukiOS\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+            url.path,
+            url.host_header()
+        );
+        let remote = IpEndpoint::new(IpAddress::Ipv4(address), url.port);
+        let mut stream = TcpStream::connect_blocking(remote, CONNECT_TIMEOUT_MS)?;
+        stream.write_all_blocking(request.as_bytes())?;
+        // The server closes the connection once the response is complete.
+        let mut raw = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let count = stream.read_blocking(&mut chunk, IDLE_TIMEOUT_MS)?;
+            if count == 0 {
+                return parse_response(&raw);
+            }
+            raw.extend_from_slice(&chunk[..count]);
+            if raw.len() > MAX_RESPONSE_SIZE {
+                return Err("Response too large.");
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
 
-        todo!();
+fn parse_response(raw: &[u8]) -> Result<Response, &'static str> {
+    let header_end = find(raw, b"\r\n\r\n").ok_or("Malformed HTTP response.")?;
+    let head = core::str::from_utf8(&raw[..header_end]).map_err(|_| "Malformed HTTP headers.")?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().ok_or("Malformed HTTP response.")?;
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts
+        .next()
+        .map_or(false, |version| version.starts_with("HTTP/1."))
+    {
+        return Err("Not an HTTP/1.x response.");
+    }
+    let status = parts
+        .next()
+        .and_then(|status| status.parse().ok())
+        .ok_or("Malformed HTTP status line.")?;
+    let reason = parts.next().unwrap_or("").to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut response = Response {
+        status,
+        reason,
+        headers,
+        body: Vec::new(),
+    };
+    let body = &raw[header_end + 4..];
+    let chunked = response
+        .header("Transfer-Encoding")
+        .map_or(false, |encoding| encoding.eq_ignore_ascii_case("chunked"));
+    response.body = if chunked {
+        decode_chunked(body)?
+    } else if let Some(length) = response.header("Content-Length") {
+        let length: usize = length.parse().map_err(|_| "Invalid Content-Length.")?;
+        if body.len() < length {
+            return Err("Connection closed before the body was complete.");
+        }
+        body[..length].to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(response)
+}
+
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = find(data, b"\r\n").ok_or("Truncated chunked body.")?;
+        let size_line =
+            core::str::from_utf8(&data[..line_end]).map_err(|_| "Malformed chunk size.")?;
+        // Chunk extensions after `;` carry nothing we need.
+        let size_text = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_text, 16).map_err(|_| "Malformed chunk size.")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        // The size comes from the server; keep it from overflowing.
+        if size > MAX_RESPONSE_SIZE {
+            return Err("Chunk too large.");
+        }
+        match size.checked_add(2) {
+            Some(end) if end <= data.len() => {}
+            _ => return Err("Truncated chunked body."),
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+pub fn fetch(args: &[&str]) {
+    let (url, output) = match args {
+        [url] => (*url, None),
+        [url, "-o", file] | ["-o", file, url] => (*url, Some(*file)),
+        _ => {
+            println!("Usage: fetch <url> [-o file]");
+            return;
+        }
+    };
+    let response = match Request::get(url) {
+        Ok(response) => response,
+        Err(err) => {
+            println!("fetch: {}: {}", url, err);
+            return;
+        }
+    };
+    if !response.is_success() {
+        println!(
+            "fetch: {}: HTTP {} {}",
+            url, response.status, response.reason
+        );
+        return;
+    }
+    match output {
+        Some(file) => match vfs::write(file, &response.body) {
+            Ok(count) => println!("Saved {} bytes to {}.", count, file),
+            Err(err) => println!("fetch: {}: {}", file, err),
+        },
+        None => println!("{}", response.text()),
     }
 }
@@ -0,0 +1,274 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    clock::{self, DateTime},
+    println,
+};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Set in every address write, so an NMI can't land between address and data,
+/// and cleared again once the access is done.
+const NMI_DISABLE: u8 = 0x80;
+/// Polls of the update flag before giving up; an update takes about 2 ms.
+const UPDATE_POLLS: usize = 100_000;
+/// Reads of all registers that may disagree before giving up.
+const STABLE_READS: usize = 8;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+/// Where PC firmware, QEMU's included, keeps the century.
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+        let value = Port::<u8>::new(CMOS_DATA).read();
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        value
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// The time registers as the chip holds them, in its own format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl Registers {
+    fn read() -> Result<Registers, &'static str> {
+        // The chip updates about once a second and its registers are
+        // inconsistent for the ~2 ms that takes. Without a chip the flag
+        // reads as set forever.
+        let mut polls = 0;
+        while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            polls += 1;
+            if polls == UPDATE_POLLS {
+                return Err("The RTC is not responding.");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(Registers {
+            second: read_register(SECONDS),
+            minute: read_register(MINUTES),
+            hour: read_register(HOURS),
+            day: read_register(DAY),
+            month: read_register(MONTH),
+            year: read_register(YEAR),
+            century: read_register(CENTURY),
+        })
+    }
+
+    /// Reads until two reads in a row agree, in case an update started
+    /// while we were reading.
+    fn read_stable() -> Result<Registers, &'static str> {
+        let mut last = Registers::read()?;
+        for _ in 0..STABLE_READS {
+            let next = Registers::read()?;
+            if next == last {
+                return Ok(next);
+            }
+            last = next;
+        }
+        Err("The RTC is not responding.")
+    }
+
+    /// Decodes the registers given status register B's format bits.
+    fn to_date_time(self, status_b: u8) -> DateTime {
+        let decode = |value: u8| {
+            if status_b & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                from_bcd(value)
+            }
+        };
+        let mut hour = decode(self.hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is hour 0, 12 PM is hour 12.
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = match decode(self.century) {
+            century @ 19..=21 => u32::from(century),
+            _ => 20,
+        };
+        DateTime {
+            year: century * 100 + u32::from(decode(self.year)),
+            month: decode(self.month),
+            day: decode(self.day),
+            hour,
+            minute: decode(self.minute),
+            second: decode(self.second),
+        }
+    }
+
+    /// Encodes `time` in status register B's format.
+    fn from_date_time(time: &DateTime, status_b: u8) -> Registers {
+        let encode = |value: u8| {
+            if status_b & STATUS_B_BINARY != 0 {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+        let hour = if status_b & STATUS_B_24_HOUR != 0 {
+            encode(time.hour)
+        } else {
+            let pm = if time.hour >= 12 { HOUR_PM } else { 0 };
+            let hour = match time.hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            encode(hour) | pm
+        };
+        Registers {
+            second: encode(time.second),
+            minute: encode(time.minute),
+            hour,
+            day: encode(time.day),
+            month: encode(time.month),
+            year: encode((time.year % 100) as u8),
+            century: encode((time.year / 100) as u8),
+        }
+    }
+}
+
+/// The date and time the RTC holds, taken to be UTC. A missing chip reads as
+/// all ones, which is no valid time either.
+pub fn read() -> Result<DateTime, &'static str> {
+    let time = interrupts::without_interrupts(|| {
+        Registers::read_stable().map(|registers| registers.to_date_time(read_register(STATUS_B)))
+    })?;
+    if !time.is_valid() {
+        return Err("The RTC holds no valid time.");
+    }
+    Ok(time)
+}
+
+/// Sets the RTC to `time`, which must be in the years 1900-2199.
+pub fn write(time: &DateTime) -> Result<(), &'static str> {
+    if !(1900..2200).contains(&time.year) {
+        return Err("The RTC only holds the years 1900-2199.");
+    }
+    interrupts::without_interrupts(|| {
+        let status_b = read_register(STATUS_B);
+        let registers = Registers::from_date_time(time, status_b);
+        // Holding SET stops updates while the registers are inconsistent.
+        write_register(STATUS_B, status_b | STATUS_B_SET);
+        write_register(SECONDS, registers.second);
+        write_register(MINUTES, registers.minute);
+        write_register(HOURS, registers.hour);
+        write_register(DAY, registers.day);
+        write_register(MONTH, registers.month);
+        write_register(YEAR, registers.year);
+        write_register(CENTURY, registers.century);
+        write_register(STATUS_B, status_b & !STATUS_B_SET);
+    });
+    Ok(())
+}
+
+pub fn hwclock(args: &[&str]) {
+    match args {
+        [] | ["-r"] | ["--show"] => match read() {
+            Ok(time) => println!("{}", time),
+            Err(err) => println!("hwclock: {}", err),
+        },
+        ["-s"] | ["--hctosys"] => match read() {
+            Ok(time) => {
+                clock::set_millis(time.to_unix() * 1000);
+                println!("System clock set to {}", time);
+            }
+            Err(err) => println!("hwclock: {}", err),
+        },
+        ["-w"] | ["--systohc"] => match clock::now() {
+            Some(now) => match write(&now) {
+                Ok(()) => println!("RTC set to {}", now),
+                Err(err) => println!("hwclock: {}", err),
+            },
+            None => println!("hwclock: The system clock is not set."),
+        },
+        _ => {
+            println!("Usage: hwclock [-r | -s | -w]");
+            println!("  -r, --show     print the RTC time");
+            println!("  -s, --hctosys  set the system clock from the RTC");
+            println!("  -w, --systohc  set the RTC from the system clock");
+        }
+    }
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    let registers = Registers {
+        second: 0x59,
+        minute: 0x30,
+        hour: HOUR_PM | 0x12,
+        day: 0x29,
+        month: 0x02,
+        year: 0x24,
+        century: 0x20,
+    };
+    let time = registers.to_date_time(0);
+    assert_eq!(
+        (
+            time.year,
+            time.month,
+            time.day,
+            time.hour,
+            time.minute,
+            time.second
+        ),
+        (2024, 2, 29, 12, 30, 59)
+    );
+    let midnight = Registers {
+        hour: 0x12,
+        ..registers
+    }
+    .to_date_time(0);
+    assert_eq!(midnight.hour, 0);
+    assert_eq!(Registers::from_date_time(&time, 0), registers);
+}
+
+#[test_case]
+fn test_binary_24_hour_round_trip() {
+    let status_b = STATUS_B_BINARY | STATUS_B_24_HOUR;
+    let time = DateTime::from_unix(1_700_000_000);
+    let registers = Registers::from_date_time(&time, status_b);
+    assert_eq!(registers.hour, 22);
+    assert_eq!(registers.to_date_time(status_b), time);
+}
@@ -1,4 +1,8 @@
-use super::{Task, TaskId};
+use super::{
+    forget_state, has_pending_tasks, set_state, take_pending_tasks, timer, Task, TaskId,
+    TaskState,
+};
+use crate::{exceptions, println};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
@@ -22,16 +26,21 @@ impl Executor {
         if self.tasks.insert(task_id, task).is_some() {
             panic!("ERROR: TASK with the same ID already exists!");
         }
+        set_state(task_id, TaskState::Ready);
         self.task_queue.push(task_id).expect("INFO: Queue full.");
     }
     pub fn run(&mut self) -> ! {
         loop {
             self.sleep_if_idle();
+            timer::wake_expired();
             self.run_ready_tasks();
         }
     }
 
     fn run_ready_tasks(&mut self) {
+        for task in take_pending_tasks() {
+            self.spawn(task);
+        }
         let Self {
             tasks,
             task_queue,
@@ -47,12 +56,22 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
+            set_state(task_id, TaskState::Running);
+            match exceptions::catch(|| task.poll(&mut context)) {
+                Ok(Poll::Ready(())) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    forget_state(task_id);
+                }
+                Ok(Poll::Pending) => set_state(task_id, TaskState::Waiting),
+                Err(fault) => {
+                    println!("[FAIL] Task {} terminated by {}", task_id.0, fault);
+                    // The future stopped halfway through a poll, so dropping
+                    // it could free what it already moved away.
+                    core::mem::forget(tasks.remove(&task_id));
+                    waker_cache.remove(&task_id);
+                    forget_state(task_id);
                 }
-                Poll::Pending => {}
             }
         }
     }
@@ -60,7 +79,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && !has_pending_tasks() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -0,0 +1,228 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    arch::global_asm,
+    future::Future,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::task::noop_waker;
+
+use crate::{
+    exceptions::{self, Recovery},
+    interrupts::{set_line_reader, LineReader},
+    vga_buffer::{swap_output, Output},
+};
+
+/// Stack for each job. Commands keep their big buffers on the heap.
+const STACK_SIZE: usize = 64 * 1024;
+/// Kept at the bottom of every job stack and checked after every switch, so
+/// an overflow shows up as itself rather than as heap corruption later.
+const STACK_CANARY: u64 = 0x6A6F_625F_7374_6B21;
+
+// A job runs on its own stack. `job_switch` saves the callee-saved registers
+// on the current stack, stores the stack pointer through rdi and continues on
+// the stack in rsi, which was left the same way. A new stack is laid out so
+// that the first switch to it "returns" into `job_start`.
+global_asm!(
+    "
+    .global job_switch
+    job_switch:
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        mov [rdi], rsp
+        mov rsp, rsi
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp
+        ret
+
+    // r12 holds the job.
+    .global job_start
+    job_start:
+        mov rdi, r12
+        call job_main
+        ud2
+    "
+);
+
+extern "C" {
+    fn job_switch(save: *mut u64, to: u64);
+    fn job_start();
+}
+
+/// What `print!`, `read_line` and `catch` use. Every job has its own, put in
+/// place while it runs.
+#[derive(Default)]
+struct Locals {
+    output: Output,
+    line_reader: Option<LineReader>,
+    recovery: Recovery,
+}
+
+impl Locals {
+    /// Trades the running code's locals for these.
+    fn swap(&mut self) {
+        self.output = swap_output(core::mem::take(&mut self.output));
+        self.line_reader = set_line_reader(self.line_reader.take());
+        self.recovery = exceptions::swap_recovery(core::mem::take(&mut self.recovery));
+    }
+}
+
+struct Inner {
+    stack: Vec<u64>,
+    /// Where the job stopped, while it isn't running.
+    stack_pointer: u64,
+    /// Where to go back to when the job suspends or finishes.
+    caller: u64,
+    body: Option<Box<dyn FnOnce()>>,
+    locals: Locals,
+    finished: bool,
+}
+
+/// Synchronous code, such as a shell command, that can [`suspend`] halfway
+/// and be resumed later, so the task running it can await in between.
+pub struct Job(Box<Inner>);
+
+/// The job running right now, null outside of jobs.
+static CURRENT: AtomicPtr<Inner> = AtomicPtr::new(ptr::null_mut());
+
+impl Job {
+    pub fn new(body: impl FnOnce() + 'static) -> Job {
+        let mut job = Box::new(Inner {
+            stack: vec![0; STACK_SIZE / 8],
+            stack_pointer: 0,
+            caller: 0,
+            body: Some(Box::new(body)),
+            locals: Locals::default(),
+            finished: false,
+        });
+        job.stack[0] = STACK_CANARY;
+        let job_address = &*job as *const Inner as u64;
+        // What `job_switch` pops: r15, r14, r13, r12, rbx, rbp and the
+        // return address, leaving the stack 16-byte aligned in `job_start`.
+        let end = job.stack.as_ptr() as u64 + STACK_SIZE as u64;
+        let top = job.stack.len() - (end % 16 / 8) as usize;
+        let frame = [0, 0, 0, job_address, 0, 0, job_start as usize as u64];
+        job.stack[top - frame.len()..top].copy_from_slice(&frame);
+        job.stack_pointer = &job.stack[top - frame.len()] as *const u64 as u64;
+        Job(job)
+    }
+
+    /// Runs the job until it suspends or finishes, and tells whether it has
+    /// finished.
+    pub fn resume(&mut self) -> bool {
+        if self.0.finished {
+            return true;
+        }
+        let job: *mut Inner = &mut *self.0;
+        let outer = CURRENT.swap(job, Ordering::SeqCst);
+        unsafe {
+            (*job).locals.swap();
+            job_switch(&mut (*job).caller, (*job).stack_pointer);
+            (*job).locals.swap();
+        }
+        CURRENT.store(outer, Ordering::SeqCst);
+        if self.0.stack[0] != STACK_CANARY {
+            panic!("Job stack overflow.");
+        }
+        self.0.finished
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        if !self.0.finished {
+            // Its frames are still on the stack, owning memory and perhaps
+            // holding locks; freeing it under them would be worse than a leak.
+            core::mem::forget(core::mem::take(&mut self.0.stack));
+        }
+    }
+}
+
+/// Goes back from the running job to whoever resumed it. Outside a job this
+/// does nothing and returns false.
+pub fn suspend() -> bool {
+    let job = CURRENT.load(Ordering::SeqCst);
+    if job.is_null() {
+        return false;
+    }
+    unsafe { job_switch(&mut (*job).stack_pointer, (*job).caller) };
+    true
+}
+
+/// Whether the running code is a job, and so may [`suspend`].
+pub fn in_job() -> bool {
+    !CURRENT.load(Ordering::SeqCst).is_null()
+}
+
+/// Resumes `job` until it finishes, letting the other tasks run whenever it
+/// suspends.
+pub async fn run(mut job: Job) {
+    while !job.resume() {
+        super::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Waits for `future` from synchronous code, suspending the job until it is
+/// ready. Outside a job waiting would stall every task, so it fails instead.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output, &'static str> {
+    if !in_job() {
+        return Err("Can only wait in a job.");
+    }
+    let mut future = Box::pin(future);
+    // Whoever resumes the job has it poll again; there is nothing to wake.
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Ok(output);
+        }
+        suspend();
+    }
+}
+
+#[no_mangle]
+extern "C" fn job_main(job: *mut u8) -> ! {
+    let job = job as *mut Inner;
+    let body = unsafe { (*job).body.take() };
+    if let Some(body) = body {
+        // A fault ends the job; its caller carries on.
+        let _ = exceptions::catch(body);
+    }
+    unsafe {
+        (*job).finished = true;
+        job_switch(&mut (*job).stack_pointer, (*job).caller);
+    }
+    unreachable!("Finished job resumed.");
+}
+
+#[test_case]
+fn test_suspend_and_resume() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    let steps = Arc::new(AtomicUsize::new(0));
+    let job_steps = steps.clone();
+    let mut job = Job::new(move || {
+        job_steps.fetch_add(1, Ordering::SeqCst);
+        assert!(in_job());
+        assert!(suspend());
+        job_steps.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(!job.resume());
+    assert_eq!(steps.load(Ordering::SeqCst), 1);
+    assert!(job.resume());
+    assert_eq!(steps.load(Ordering::SeqCst), 2);
+    assert!(!in_job());
+    assert!(!suspend());
+}
@@ -1,12 +1,18 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use core::{
     future::Future,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
+use lazy_static::lazy_static;
+use spin::Mutex;
+pub mod job;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
+
+pub use timer::{sleep, sleep_until, timeout};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -30,9 +36,60 @@ impl Task {
     }
 }
 
+/// Tasks handed to [`spawn`] until an executor picks them up. KukiOS runs on a
+/// single core, so the futures never actually move between threads.
+struct PendingTasks(Vec<Task>);
+
+unsafe impl Send for PendingTasks {}
+
+lazy_static! {
+    static ref PENDING_TASKS: Mutex<PendingTasks> = Mutex::new(PendingTasks(Vec::new()));
+}
+
+/// Starts `future` as a new task on the running executor.
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    PENDING_TASKS.lock().0.push(Task::new(future));
+}
+
+fn take_pending_tasks() -> Vec<Task> {
+    core::mem::take(&mut PENDING_TASKS.lock().0)
+}
+
+fn has_pending_tasks() -> bool {
+    !PENDING_TASKS.lock().0.is_empty()
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Waiting,
+}
+
+lazy_static! {
+    static ref TASK_STATES: Mutex<BTreeMap<TaskId, TaskState>> = Mutex::new(BTreeMap::new());
+}
+
+fn set_state(task_id: TaskId, state: TaskState) {
+    TASK_STATES.lock().insert(task_id, state);
+}
+
+fn forget_state(task_id: TaskId) {
+    TASK_STATES.lock().remove(&task_id);
+}
+
+/// Ids and states of all tasks an executor currently owns.
+pub fn task_states() -> Vec<(u64, TaskState)> {
+    TASK_STATES
+        .lock()
+        .iter()
+        .map(|(task_id, state)| (task_id.0, *state))
+        .collect()
+}
@@ -0,0 +1,127 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::interrupts::uptime_millis;
+
+lazy_static! {
+    /// Sleeping tasks by deadline in uptime milliseconds, then by timer id.
+    static ref TIMERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+}
+
+/// The earliest deadline in `TIMERS`, so most checks can skip the lock.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Wakes every task whose deadline has passed. The timer interrupt only moves
+/// the tick count; the executor calls this each time it wakes up, so wakers
+/// run and timers are freed outside interrupt context.
+pub fn wake_expired() {
+    let now = uptime_millis();
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut timers = TIMERS.lock();
+    let pending = timers.split_off(&(now + 1, 0));
+    let expired = core::mem::replace(&mut *timers, pending);
+    let next = timers
+        .keys()
+        .next()
+        .map_or(u64::MAX, |(deadline, _)| *deadline);
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+    drop(timers);
+    for waker in expired.into_values() {
+        waker.wake();
+    }
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_millis().min(u128::from(u64::MAX / 2)) as u64
+}
+
+/// Resolves after `duration`, with the timer's millisecond resolution.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(uptime_millis() + duration_millis(duration))
+}
+
+/// Resolves once [`uptime_millis`] reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    fn unregister(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&(self.deadline, self.id));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if uptime_millis() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        TIMERS
+            .lock()
+            .insert((self.deadline, self.id), cx.waker().clone());
+        self.registered = true;
+        NEXT_DEADLINE.fetch_min(self.deadline, Ordering::Relaxed);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Runs `future` for at most `duration`. Gives `Err("Timed out.")` if it
+/// didn't finish in time; the future is dropped then.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, &'static str>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err("Timed out.")),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use alloc::{string::String, vec, vec::Vec};
+
+use crate::{devfs, mem_filesystem::FILESYSTEM, procfs};
+
+enum Path<'a> {
+    Device(&'a str),
+    DeviceDirectory,
+    Proc(&'a str),
+    ProcDirectory,
+    File(&'a str),
+    Root,
+}
+
+fn resolve(path: &str) -> Path {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        Path::Root
+    } else if path == "dev" {
+        Path::DeviceDirectory
+    } else if let Some(name) = path.strip_prefix("dev/") {
+        Path::Device(name)
+    } else if path == "proc" {
+        Path::ProcDirectory
+    } else if let Some(name) = path.strip_prefix("proc/") {
+        Path::Proc(name)
+    } else {
+        Path::File(path)
+    }
+}
+
+/// Whether `path` names a file in the filesystem, as opposed to a device, a
+/// generated `/proc` file or a directory. It need not exist yet.
+pub fn is_file(path: &str) -> bool {
+    matches!(resolve(path), Path::File(_))
+}
+
+/// Whether `path` names something under `/dev`.
+pub fn is_device(path: &str) -> bool {
+    matches!(resolve(path), Path::Device(_))
+}
+
+/// Size of the file at `path`, `None` if it is missing or an endless device.
+pub fn size(path: &str) -> Option<usize> {
+    match resolve(path) {
+        Path::Device(name) => devfs::lookup(name)?.size(),
+        Path::Proc(name) => Some(procfs::lookup(name)?().len()),
+        Path::File(name) => FILESYSTEM.lock().file_size(name),
+        Path::DeviceDirectory | Path::ProcDirectory | Path::Root => None,
+    }
+}
+
+pub fn read_at(path: &str, offset: usize, buffer: &mut [u8]) -> Result<usize, &'static str> {
+    match resolve(path) {
+        Path::Device(name) => devfs::lookup(name)
+            .ok_or("No such device.")?
+            .read(offset, buffer),
+        Path::Proc(name) => {
+            let content = procfs::lookup(name).ok_or("No such file.")?();
+            let content = content.as_bytes();
+            if offset >= content.len() {
+                return Ok(0);
+            }
+            let count = usize::min(content.len() - offset, buffer.len());
+            buffer[..count].copy_from_slice(&content[offset..offset + count]);
+            Ok(count)
+        }
+        Path::File(name) => {
+            let fs = FILESYSTEM.lock();
+            let size = fs.file_size(name).ok_or("File not found.")?;
+            let mut data = vec![0u8; size];
+            fs.read_file_by_name(name, &mut data);
+            if offset >= size {
+                return Ok(0);
+            }
+            let count = usize::min(size - offset, buffer.len());
+            buffer[..count].copy_from_slice(&data[offset..offset + count]);
+            Ok(count)
+        }
+        Path::DeviceDirectory | Path::ProcDirectory | Path::Root => Err("Is a directory."),
+    }
+}
+
+/// Reads a whole file, or `limit` bytes of it. Devices without a size, from
+/// endless streams to whole disks, can only be read with a limit.
+pub fn read(path: &str, limit: Option<usize>) -> Result<Vec<u8>, &'static str> {
+    if let Path::Proc(name) = resolve(path) {
+        // Generated once, so the length and the bytes come from one snapshot.
+        let mut data = procfs::lookup(name).ok_or("No such file.")?().into_bytes();
+        if let Some(limit) = limit {
+            data.truncate(limit);
+        }
+        return Ok(data);
+    }
+    let length = match (size(path), limit) {
+        (Some(size), Some(limit)) => usize::min(size, limit),
+        (Some(size), None) => size,
+        (None, Some(limit)) => limit,
+        (None, None) => {
+            return Err(match resolve(path) {
+                Path::Device(name) if devfs::lookup(name).is_some() => {
+                    "Device has no size, give a byte count."
+                }
+                Path::Device(_) => "No such device.",
+                Path::File(_) => "File not found.",
+                _ => "Is a directory.",
+            })
+        }
+    };
+    let mut data = vec![0u8; length];
+    let mut done = 0;
+    while done < length {
+        let count = read_at(path, done, &mut data[done..])?;
+        if count == 0 {
+            break;
+        }
+        done += count;
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+/// The most [`write`] takes for `path`. Devices set no limit of their own.
+pub fn max_file_size(path: &str) -> Result<usize, &'static str> {
+    match resolve(path) {
+        Path::Device(name) => devfs::lookup(name)
+            .map(|_| usize::MAX)
+            .ok_or("No such device."),
+        Path::Proc(_) => Err("/proc is read-only."),
+        Path::File(_) => Ok(FILESYSTEM.lock().max_file_size()),
+        Path::DeviceDirectory | Path::ProcDirectory | Path::Root => Err("Is a directory."),
+    }
+}
+
+/// Replaces the contents of `path`, creating the file if needed.
+pub fn write(path: &str, data: &[u8]) -> Result<usize, &'static str> {
+    match resolve(path) {
+        Path::Device(name) => devfs::lookup(name)
+            .ok_or("No such device.")?
+            .write(0, data),
+        Path::Proc(_) => Err("/proc is read-only."),
+        Path::File(name) => {
+            let mut fs = FILESYSTEM.lock();
+            if data.len() > fs.max_file_size() {
+                return Err("File too large!");
+            }
+            let inode = match fs.find_file(name) {
+                Some(inode) => inode,
+                None => fs
+                    .create_file(data.len() as u32, name)
+                    .ok_or("Could not create the file.")?,
+            };
+            fs.write_file(inode, data);
+            Ok(data.len())
+        }
+        Path::DeviceDirectory | Path::ProcDirectory | Path::Root => Err("Is a directory."),
+    }
+}
+
+pub fn list(path: &str) -> Result<Vec<String>, &'static str> {
+    match resolve(path) {
+        Path::Root => {
+            let mut entries = vec![String::from("dev/"), String::from("proc/")];
+            entries.extend(FILESYSTEM.lock().list_files());
+            Ok(entries)
+        }
+        Path::DeviceDirectory => Ok(devfs::names().map(String::from).collect()),
+        Path::ProcDirectory => Ok(procfs::names().map(String::from).collect()),
+        Path::Device(_) | Path::Proc(_) | Path::File(_) => Err("Not a directory."),
+    }
+}
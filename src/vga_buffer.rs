@@ -1,3 +1,4 @@
+use alloc::{string::String, sync::Arc};
 use core::fmt::{self, Write};
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -157,8 +158,66 @@ pub fn _print(args: fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // A handler interrupts whoever is running, perhaps a remote session's
+        // command; its keyboard echo and log lines belong on the screen.
+        if crate::interrupts::in_irq() {
+            WRITER.lock().write_fmt(args).unwrap();
+            return;
+        }
+        let mut output = OUTPUT.lock();
+        if let Some(captured) = output.capture.as_mut() {
+            captured.write_fmt(args).unwrap();
+        } else if let Some(stream) = output.stream.as_ref() {
+            stream.lock().write_fmt(args).unwrap();
+        } else {
+            WRITER.lock().write_fmt(args).unwrap();
+        }
+    });
+}
+
+/// Text printed for someone other than the screen, e.g. a remote session,
+/// which takes it out as it arrives.
+pub type Stream = Arc<Mutex<String>>;
+
+/// Where `print!` goes instead of the screen, if anywhere.
+#[derive(Default)]
+pub struct Output {
+    capture: Option<String>,
+    stream: Option<Stream>,
+}
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output::default());
+}
+
+/// Puts `output` in place and gives back the one it replaces. Lets a job
+/// take its own output along when it is suspended.
+pub fn swap_output(output: Output) -> Output {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        core::mem::replace(&mut *OUTPUT.lock(), output)
+    })
+}
+
+/// Sends everything printed to `stream` until it is replaced, and gives back
+/// the stream it replaces.
+pub fn set_output_stream(stream: Option<Stream>) -> Option<Stream> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        core::mem::replace(&mut OUTPUT.lock().stream, stream)
+    })
+}
+
+/// Runs `f` with everything it prints collected into a string instead of the screen.
+pub fn capture<F: FnOnce()>(f: F) -> String {
+    let previous = x86_64::instructions::interrupts::without_interrupts(|| {
+        OUTPUT.lock().capture.replace(String::new())
     });
+    f();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut output = OUTPUT.lock();
+        let captured = output.capture.take().unwrap_or_default();
+        output.capture = previous;
+        captured
+    })
 }
 
 #[test_case]
@@ -0,0 +1,186 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kukios::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Poll,
+};
+use futures_util::future::poll_fn;
+use kukios::{
+    net::{self, httpd, TcpListener, TcpStream, UdpSocket},
+    task::{simple_executor::SimpleExecutor, Task},
+};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kukios::allocator;
+    use kukios::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kukios::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("SERIOUS EXCEPTION: Heap init failed.");
+    memory::install(mapper, frame_allocator);
+    // No PCI probing, so the stack comes up with only `lo`.
+    net::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kukios::test_panic_handler(info)
+}
+
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs both tasks to completion next to one that keeps polling the stack,
+/// since the real network task only runs on the full executor. Each task bumps
+/// `FINISHED` when it is done.
+fn run_with_network(tasks: [Task; 2]) {
+    FINISHED.store(0, Ordering::SeqCst);
+    let mut executor = SimpleExecutor::new();
+    for task in tasks {
+        executor.spawn(task);
+    }
+    executor.spawn(Task::new(poll_fn(|_| {
+        net::poll();
+        if FINISHED.load(Ordering::SeqCst) == 2 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })));
+    executor.run();
+}
+
+fn localhost(port: u16) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::v4(127, 0, 0, 1), port)
+}
+
+#[test_case]
+fn udp_round_trip() {
+    run_with_network([
+        Task::new(async {
+            let server = UdpSocket::bind(7001).unwrap();
+            let mut buffer = [0u8; 64];
+            let (count, from) = server.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..count], b"ping");
+            server.send_to(b"pong", from).await.unwrap();
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+        Task::new(async {
+            let client = UdpSocket::bind(0).unwrap();
+            client.send_to(b"ping", localhost(7001)).await.unwrap();
+            let mut buffer = [0u8; 64];
+            let (count, _) = client.recv_from(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..count], b"pong");
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+    ]);
+}
+
+#[test_case]
+fn tcp_echo() {
+    run_with_network([
+        Task::new(async {
+            let mut listener = TcpListener::bind(7002).unwrap();
+            let mut stream = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 64];
+            let count = stream.read(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..count], b"hello");
+            stream.write_all(b"world").await.unwrap();
+            stream.flush().await.unwrap();
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+        Task::new(async {
+            let mut stream = TcpStream::connect(localhost(7002)).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut reply = Vec::new();
+            let mut buffer = [0u8; 64];
+            while reply.len() < 5 {
+                let count = stream.read(&mut buffer).await.unwrap();
+                assert!(count > 0, "connection closed early");
+                reply.extend_from_slice(&buffer[..count]);
+            }
+            assert_eq!(&reply[..], b"world");
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+    ]);
+}
+
+/// Sends one HTTP/1.0 request to the server on port 7003 and returns the reply.
+async fn http_request(request: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(localhost(7003)).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut reply = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let count = stream.read(&mut buffer).await.unwrap();
+        if count == 0 {
+            return reply;
+        }
+        reply.extend_from_slice(&buffer[..count]);
+    }
+}
+
+#[test_case]
+fn httpd_lists_root_and_reports_missing_files() {
+    run_with_network([
+        Task::new(async {
+            let mut listener = TcpListener::bind(7003).unwrap();
+            for _ in 0..3 {
+                let stream = listener.accept().await.unwrap();
+                httpd::serve(stream).await;
+            }
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+        Task::new(async {
+            let listing = http_request(b"GET / HTTP/1.0\r\n\r\n").await;
+            assert!(listing.starts_with(b"HTTP/1.0 200 OK\r\n"));
+            assert!(!listing
+                .windows(12)
+                .any(|window| window == b"href=\"/dev/\""));
+            let devices = http_request(b"GET /dev/ HTTP/1.0\r\n\r\n").await;
+            assert!(devices.starts_with(b"HTTP/1.0 403 Forbidden\r\n"));
+            let missing = http_request(b"GET /no-such-file HTTP/1.0\r\n\r\n").await;
+            assert!(missing.starts_with(b"HTTP/1.0 404 Not Found\r\n"));
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        }),
+    ]);
+}
+
+#[test_case]
+fn loopback_is_configured() {
+    let (lo, eth0) = net::with_stack(|stack| {
+        (
+            stack.lo.ip_addrs().to_vec(),
+            stack.iface.ip_addrs().to_vec(),
+        )
+    })
+    .unwrap();
+    assert!(lo
+        .iter()
+        .any(|cidr| cidr.address() == IpAddress::v4(127, 0, 0, 1)));
+    assert!(lo
+        .iter()
+        .any(|cidr| cidr.address() == IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1)));
+    assert!(!eth0.iter().any(|cidr| match cidr.address() {
+        IpAddress::Ipv4(address) => address.is_loopback(),
+        IpAddress::Ipv6(address) => address.is_loopback(),
+        _ => false,
+    }));
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kukios::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, task::Wake};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Waker},
+    time::Duration,
+};
+use kukios::{
+    interrupts::uptime_millis,
+    task::{self, simple_executor::SimpleExecutor, Task},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use kukios::allocator;
+    use kukios::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kukios::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("SERIOUS EXCEPTION: Heap init failed.");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kukios::test_panic_handler(info)
+}
+
+static SHORT_DONE_AT: AtomicU64 = AtomicU64::new(0);
+static LONG_DONE_AT: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn sleeps_end_in_deadline_order() {
+    let start = uptime_millis();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        task::sleep(Duration::from_millis(30)).await;
+        LONG_DONE_AT.store(uptime_millis(), Ordering::SeqCst);
+    }));
+    executor.spawn(Task::new(async {
+        task::sleep(Duration::from_millis(10)).await;
+        SHORT_DONE_AT.store(uptime_millis(), Ordering::SeqCst);
+    }));
+    executor.run();
+    let (short, long) = (
+        SHORT_DONE_AT.load(Ordering::SeqCst),
+        LONG_DONE_AT.load(Ordering::SeqCst),
+    );
+    assert!(short - start >= 10);
+    assert!(long - start >= 30);
+    assert!(short < long);
+}
+
+#[test_case]
+fn timeout_cuts_off_slow_futures() {
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let slow = task::timeout(
+            Duration::from_millis(10),
+            task::sleep(Duration::from_secs(5)),
+        );
+        assert_eq!(slow.await, Err("Timed out."));
+        let fast = task::timeout(Duration::from_secs(5), async { 42 });
+        assert_eq!(fast.await, Ok(42));
+    }));
+    executor.run();
+}
+
+struct FlagWaker(AtomicBool);
+
+impl Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn wake_expired_wakes_only_due_timers() {
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut sleep = task::sleep(Duration::from_millis(5));
+    let poll = Pin::new(&mut sleep).poll(&mut Context::from_waker(&waker));
+    assert!(poll.is_pending());
+    task::timer::wake_expired();
+    assert!(!flag.0.load(Ordering::SeqCst));
+    kukios::sleep(10);
+    task::timer::wake_expired();
+    assert!(flag.0.load(Ordering::SeqCst));
+}